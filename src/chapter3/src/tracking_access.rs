@@ -242,8 +242,9 @@ impl Scheduler {
     pub fn run(&mut self) {
         for system in self.systems.iter_mut() {
             system.run(&self.resources, &mut self.accesses);
+            // Systems run one after another, so only accesses within the same system can conflict.
+            self.accesses.clear();
         }
-        self.accesses.clear();
     }
 
     pub fn add_system<I, S: System + 'static>(&mut self, system: impl IntoSystem<I, System = S>) {
@@ -0,0 +1,4670 @@
+// ANCHOR: All
+use std::alloc::{GlobalAlloc, Layout};
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
+
+// ANCHOR: TypeMap
+type TypeMap = HashMap<TypeId, UnsafeCell<Box<dyn Any>>>;
+// ANCHOR_END: TypeMap
+
+// ANCHOR: impl_system_macro
+macro_rules! impl_system {
+    (
+        $($params:ident),*
+    ) => {
+        #[allow(non_snake_case)]
+        #[allow(unused)]
+        impl<F, Out, $($params: SystemParam),*> System for FunctionSystem<(Out, ($($params,)*)), F>
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( $($params),* ) -> Out +
+                    FnMut( $(<$params as SystemParam>::Item<'b>),* ) -> Out
+        {
+            type In = ();
+            type Out = Out;
+
+            fn name(&self) -> Cow<'static, str> {
+                Cow::Borrowed(std::any::type_name::<F>())
+            }
+
+            fn accesses(&self, accesses: &mut AccessMap) {
+                $(
+                    $params::accesses(accesses);
+                )*
+            }
+
+            fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+                $(
+                    $params::requires(required);
+                )*
+            }
+
+            fn try_run(
+                &mut self,
+                _input: (),
+                resources: &TypeMap,
+            ) -> Result<Out, SystemParamError> {
+                #[allow(clippy::too_many_arguments)]
+                fn call_inner<Out, $($params),*>(
+                    mut f: impl FnMut($($params),*) -> Out,
+                    $($params: $params),*
+                ) -> Out {
+                    f($($params),*)
+                }
+
+                // SAFETY:
+                // Every access here is proven to be nonconflicting because `into_system` called
+                // `accesses` for every parameter, which would have panicked otherwise.
+                let state = self.state.get_or_insert_with(|| {
+                    Box::new(($(<$params as SystemParam>::State::default(),)*))
+                });
+                let states = state
+                    .downcast_mut::<($(<$params as SystemParam>::State,)*)>()
+                    .unwrap();
+                let out = {
+                    let ($($params,)*) = &mut *states;
+                    $(
+                        let $params = unsafe { $params::retrieve(resources, $params) }?;
+                    )*
+                    call_inner(&mut self.f, $($params),*)
+                };
+
+                let ($($params,)*) = states;
+                $(
+                    $params::system_ran($params);
+                )*
+                Ok(out)
+            }
+        }
+
+        // ANCHOR: impl_system_with_input
+        #[allow(non_snake_case)]
+        #[allow(unused)]
+        impl<F, Input, Out, $($params: SystemParam),*> System
+            for FunctionSystem<(Out, In<Input>, ($($params,)*)), F>
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( In<Input>, $($params),* ) -> Out +
+                    FnMut( In<Input>, $(<$params as SystemParam>::Item<'b>),* ) -> Out
+        {
+            type In = Input;
+            type Out = Out;
+
+            fn name(&self) -> Cow<'static, str> {
+                Cow::Borrowed(std::any::type_name::<F>())
+            }
+
+            fn accesses(&self, accesses: &mut AccessMap) {
+                $(
+                    $params::accesses(accesses);
+                )*
+            }
+
+            fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+                $(
+                    $params::requires(required);
+                )*
+            }
+
+            fn try_run(
+                &mut self,
+                input: Input,
+                resources: &TypeMap,
+            ) -> Result<Out, SystemParamError> {
+                #[allow(clippy::too_many_arguments)]
+                fn call_inner<Input, Out, $($params),*>(
+                    mut f: impl FnMut(In<Input>, $($params),*) -> Out,
+                    input: In<Input>,
+                    $($params: $params),*
+                ) -> Out {
+                    f(input, $($params),*)
+                }
+
+                // SAFETY:
+                // Every access here is proven to be nonconflicting because `into_system` called
+                // `accesses` for every parameter, which would have panicked otherwise.
+                let state = self.state.get_or_insert_with(|| {
+                    Box::new(($(<$params as SystemParam>::State::default(),)*))
+                });
+                let states = state
+                    .downcast_mut::<($(<$params as SystemParam>::State,)*)>()
+                    .unwrap();
+                let out = {
+                    let ($($params,)*) = &mut *states;
+                    $(
+                        let $params = unsafe { $params::retrieve(resources, $params) }?;
+                    )*
+                    call_inner(&mut self.f, In(input), $($params),*)
+                };
+
+                let ($($params,)*) = states;
+                $(
+                    $params::system_ran($params);
+                )*
+                Ok(out)
+            }
+        }
+        // ANCHOR_END: impl_system_with_input
+    }
+}
+// ANCHOR_END: impl_system_macro
+
+macro_rules! impl_into_system {
+    (
+        $($params:ident),*
+    ) => {
+        impl<F, Out, $($params: SystemParam),*> IntoSystem<(Out, ($($params,)*))> for F
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( $($params),* ) -> Out +
+                    FnMut( $(<$params as SystemParam>::Item<'b>),* ) -> Out
+        {
+            type System = FunctionSystem<(Out, ($($params,)*)), Self>;
+
+            fn into_system(self) -> Self::System {
+                let system = FunctionSystem {
+                    f: self,
+                    state: None,
+                    marker: Default::default(),
+                };
+                // Check for conflicting parameters once, here, so that running doesn't have to.
+                system.accesses(&mut AccessMap::new());
+                system
+            }
+        }
+
+        impl<F, Input, Out, $($params: SystemParam),*> IntoSystem<(Out, In<Input>, ($($params,)*))> for F
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( In<Input>, $($params),* ) -> Out +
+                    FnMut( In<Input>, $(<$params as SystemParam>::Item<'b>),* ) -> Out
+        {
+            type System = FunctionSystem<(Out, In<Input>, ($($params,)*)), Self>;
+
+            fn into_system(self) -> Self::System {
+                let system = FunctionSystem {
+                    f: self,
+                    state: None,
+                    marker: Default::default(),
+                };
+                // Check for conflicting parameters once, here, so that running doesn't have to.
+                system.accesses(&mut AccessMap::new());
+                system
+            }
+        }
+    }
+}
+
+// ANCHOR: Access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+// ANCHOR_END: Access
+
+// ANCHOR: AccessMap
+/// Something a system can access. A resource and a component of the same type have nothing to do
+/// with each other, so they get keys of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum AccessKey {
+    Resource(TypeId),
+    /// One type of component, on every entity. Carries the type name, since components aren't
+    /// registered anywhere the scheduler could look it up.
+    Component(TypeId, &'static str),
+}
+
+impl AccessKey {
+    fn resource<T: ?Sized + 'static>() -> Self {
+        AccessKey::Resource(TypeId::of::<T>())
+    }
+
+    fn component<T: 'static>() -> Self {
+        AccessKey::Component(TypeId::of::<T>(), std::any::type_name::<T>())
+    }
+}
+
+type AccessMap = HashMap<AccessKey, Access>;
+// ANCHOR_END: AccessMap
+
+// ANCHOR: SystemParam
+trait SystemParam {
+    /// Storage the parameter keeps inside each system that uses it, from one run to the next.
+    /// Parameters that only borrow resources use `()`.
+    type State: Default + Send + 'static;
+
+    type Item<'new>;
+
+    /// For safety, this function must panic if there are any conflicting accesses, and it must
+    /// accurately record its accesses so that a future call can panic if there are conflicting
+    /// accesses.
+    fn accesses(access: &mut AccessMap);
+
+    /// Records the resources that have to exist for `retrieve` to succeed, along with their type
+    /// names. Parameters that can do without, like `Option<Res<T>>`, don't record anything.
+    fn requires(_required: &mut Vec<(TypeId, &'static str)>) {}
+
+    // ANCHOR: SystemParamRetrieve
+    /// Fails if something the parameter needs, such as its resource, doesn't exist.
+    ///
+    /// SAFETY:
+    /// - The caller must not have active conflicting references to resources that this function will access
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut Self::State,
+    ) -> Result<Self::Item<'r>, SystemParamError>;
+    // ANCHOR_END: SystemParamRetrieve
+
+    /// Called once the system has actually run. If a later parameter fails to `retrieve`, the
+    /// system never sees anything, so parameters keeping track of what it has seen, like
+    /// [`Changed`], only move on here.
+    fn system_ran(_state: &mut Self::State) {}
+}
+// ANCHOR_END: SystemParam
+
+// ANCHOR: SystemParamError
+/// A system couldn't run because one of its parameters wasn't available.
+#[derive(Debug, PartialEq, Eq)]
+struct SystemParamError {
+    /// The type name of the missing resource.
+    resource: &'static str,
+}
+
+impl SystemParamError {
+    fn missing<T: ?Sized>() -> Self {
+        SystemParamError {
+            resource: std::any::type_name::<T>(),
+        }
+    }
+}
+
+impl std::fmt::Display for SystemParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "resource {} does not exist", self.resource)
+    }
+}
+// ANCHOR_END: SystemParamError
+
+// ANCHOR: ResSystemParam
+impl<'res, T: 'static> SystemParam for Res<'res, T> {
+    type State = ();
+    type Item<'new> = Res<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        assert_eq!(
+            *access.entry(AccessKey::resource::<T>()).or_insert(Access::Read),
+            Access::Read,
+            "conflicting access in system; attempting to access {} mutably and immutably at the same
+            time",
+            std::any::type_name::<T>(),
+        );
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        required.push((TypeId::of::<T>(), std::any::type_name::<T>()));
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        _state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        let value = Moved::cell::<T>(resources)
+            .ok_or_else(SystemParamError::missing::<T>)?
+            .get();
+
+        // SAFETY:
+        // The caller asserts that there are no conflicting accesses, and the pointer is definitely
+        // valid as it was obtained directly from `UnsafeCell`. Its lifetime will be constrained
+        // to the lifetime of the map it was obtained from, so it cannot dangle.
+        let value = unsafe { &*value };
+
+        // Resources added with `add_shared_resource` are stored as an `Arc<T>` instead.
+        let (value, shared) = match value.downcast_ref::<Arc<T>>() {
+            Some(shared) => (&**shared, Some(shared)),
+            None => (value.downcast_ref::<T>().unwrap(), None),
+        };
+        AccessCounts::record::<T>(resources);
+
+        Ok(Res {
+            value,
+            shared,
+            _guard: BorrowGuard::new::<T>(resources),
+        })
+    }
+}
+// ANCHOR_END: ResSystemParam
+
+// ANCHOR: ResMutSystemParam
+impl<'res, T: 'static> SystemParam for ResMut<'res, T> {
+    type State = ();
+    type Item<'new> = ResMut<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        match access.insert(AccessKey::resource::<T>(), Access::Write) {
+            Some(Access::Read) => panic!(
+                "conflicting access in system; attempting to access {} mutably and immutably at the same time", 
+                std::any::type_name::<T>()
+            ),
+            Some(Access::Write) => panic!(
+                "conflicting access in system; attempting to access {} mutably twice", 
+                std::any::type_name::<T>()
+            ),
+            None => (),
+        }
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        required.push((TypeId::of::<T>(), std::any::type_name::<T>()));
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        _state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        let value = Moved::cell::<T>(resources)
+            .ok_or_else(SystemParamError::missing::<T>)?
+            .get();
+
+        // SAFETY:
+        // The caller asserts that there are no conflicting accesses, and the pointer is definitely
+        // valid as it was obtained directly from `UnsafeCell`. Its lifetime will be constrained
+        // to the lifetime of the map it was obtained from, so it cannot dangle.
+        let value = unsafe { &mut *value };
+
+        assert!(
+            !value.is::<Arc<T>>(),
+            "resource {} was added with `add_shared_resource`, so it can't be changed",
+            std::any::type_name::<T>(),
+        );
+        let value = value.downcast_mut::<T>().unwrap();
+        AccessCounts::record::<T>(resources);
+
+        Ok(ResMut {
+            value,
+            ticks: ChangeTicks::get(resources),
+            _guard: BorrowGuard::new::<T>(resources),
+        })
+    }
+}
+// ANCHOR_END: ResMutSystemParam
+
+// ANCHOR: OptionalRes
+impl<'res, T: 'static> SystemParam for Option<Res<'res, T>> {
+    type State = ();
+    type Item<'new> = Option<Res<'new, T>>;
+
+    fn accesses(access: &mut AccessMap) {
+        // A missing resource is still accessed as far as conflicts go: it might be added later.
+        Res::<T>::accesses(access);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        if Moved::cell::<T>(resources).is_none() {
+            return Ok(None);
+        }
+
+        // SAFETY: The caller upholds the same contract for us as for `Res<T>`.
+        unsafe { Res::<T>::retrieve(resources, state) }.map(Some)
+    }
+}
+
+impl<'res, T: 'static> SystemParam for Option<ResMut<'res, T>> {
+    type State = ();
+    type Item<'new> = Option<ResMut<'new, T>>;
+
+    fn accesses(access: &mut AccessMap) {
+        ResMut::<T>::accesses(access);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        if Moved::cell::<T>(resources).is_none() {
+            return Ok(None);
+        }
+
+        // SAFETY: The caller upholds the same contract for us as for `ResMut<T>`.
+        unsafe { ResMut::<T>::retrieve(resources, state) }.map(Some)
+    }
+}
+// ANCHOR_END: OptionalRes
+
+// ANCHOR: Local
+/// A value private to one system, which it keeps between runs. Starts out as `T::default()`.
+struct Local<'a, T: Default + 'static> {
+    value: &'a mut T,
+}
+
+impl<T: Default + 'static> Deref for Local<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: Default + 'static> DerefMut for Local<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'res, T: Default + Send + 'static> SystemParam for Local<'res, T> {
+    type State = T;
+    type Item<'new> = Local<'new, T>;
+
+    fn accesses(_access: &mut AccessMap) {
+        // Nobody else can see the value, so there's nothing to conflict with.
+    }
+
+    unsafe fn retrieve<'r>(
+        _resources: &'r TypeMap,
+        state: &'r mut T,
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        Ok(Local { value: state })
+    }
+}
+// ANCHOR_END: Local
+
+// ANCHOR: system_param
+/// Declares a struct whose fields are all system parameters, and makes the struct a system
+/// parameter too, so a system can take the whole bundle as one argument. The struct needs exactly
+/// one lifetime, which its fields borrow the resources for.
+macro_rules! system_param {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident<$lt:lifetime> {
+            $($field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name<$lt> {
+            $($field_vis $field: $ty),*
+        }
+
+        impl<$lt> SystemParam for $name<$lt> {
+            type State = ($(<$ty as SystemParam>::State,)*);
+            type Item<'new> = $name<'new>;
+
+            fn accesses(access: &mut AccessMap) {
+                // Every field records into the same map, so fields that conflict with each other
+                // panic just like two conflicting parameters of a system would.
+                $(
+                    <$ty as SystemParam>::accesses(access);
+                )*
+            }
+
+            fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+                $(
+                    <$ty as SystemParam>::requires(required);
+                )*
+            }
+
+            unsafe fn retrieve<'r>(
+                resources: &'r TypeMap,
+                state: &'r mut Self::State,
+            ) -> Result<Self::Item<'r>, SystemParamError> {
+                let ($($field,)*) = state;
+
+                // SAFETY:
+                // The caller asserts that nothing conflicts with our accesses, which are exactly
+                // the fields' accesses put together.
+                Ok($name {
+                    $(
+                        $field: unsafe { <$ty as SystemParam>::retrieve(resources, $field) }?,
+                    )*
+                })
+            }
+
+            fn system_ran(state: &mut Self::State) {
+                let ($($field,)*) = state;
+                $(
+                    <$ty as SystemParam>::system_ran($field);
+                )*
+            }
+        }
+    };
+}
+// ANCHOR_END: system_param
+
+// ANCHOR: Commands
+/// Queues up changes to the scheduler's resources and entities. [`Scheduler::run`] applies them as
+/// soon as the system that queued them finishes.
+struct Commands<'a> {
+    queue: &'a CommandQueue,
+    entities: &'a Entities,
+}
+
+impl Commands<'_> {
+    /// Adds `value` as a resource, replacing any existing resource of the same type.
+    pub fn insert_resource<T: 'static>(&mut self, value: T) {
+        self.queue.commands.borrow_mut().push(Command::Insert(
+            TypeId::of::<T>(),
+            std::any::type_name::<T>(),
+            Box::new(value),
+        ));
+    }
+
+    /// Removes the resource of type `T`, if there is one.
+    pub fn remove_resource<T: 'static>(&mut self) {
+        self.queue
+            .commands
+            .borrow_mut()
+            .push(Command::Remove(TypeId::of::<T>()));
+    }
+
+    /// Spawns an entity with the components in `bundle`. The id is usable right away, e.g. to
+    /// store in a resource, but queries only see the entity once the command is applied.
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Entity {
+        let entity = self.entities.reserve();
+        self.queue.commands.borrow_mut().push(Command::Spawn(
+            entity,
+            Box::new(move |entities| bundle.insert(entities, entity)),
+        ));
+        entity
+    }
+
+    /// Removes `entity` and all of its components. Does nothing if it doesn't exist by then.
+    pub fn despawn(&mut self, entity: Entity) {
+        self.queue
+            .commands
+            .borrow_mut()
+            .push(Command::Despawn(entity));
+    }
+}
+
+enum Command {
+    Insert(TypeId, &'static str, Box<dyn Any>),
+    Remove(TypeId),
+    /// Cleans up after a `ResOwned`. Not something systems can queue themselves.
+    RemovePlaceholder(TypeId),
+    Spawn(Entity, Box<dyn FnOnce(&mut Entities)>),
+    Despawn(Entity),
+}
+
+impl Command {
+    /// `None` for the scheduler's own bookkeeping, which is always applied right away.
+    fn kind(&self) -> Option<CommandKind> {
+        match self {
+            Command::Insert(..) | Command::Remove(..) => Some(CommandKind::Resource),
+            Command::Spawn(..) | Command::Despawn(..) => Some(CommandKind::Spawn),
+            Command::RemovePlaceholder(..) => None,
+        }
+    }
+}
+
+/// Where `Commands` go until they're applied. Like `AccessCounts`, it lives in the `TypeMap` so
+/// that `retrieve` can find it.
+#[derive(Default)]
+struct CommandQueue {
+    commands: RefCell<Vec<Command>>,
+    /// The kinds of command that wait for a [`FlushPoint`] instead of being applied right after
+    /// the system that queued them.
+    held: HashSet<CommandKind>,
+    /// The kinds of command a flush point has asked to apply once it's done.
+    flushing: RefCell<HashSet<CommandKind>>,
+}
+
+impl<'res> SystemParam for Commands<'res> {
+    type State = ();
+    type Item<'new> = Commands<'new>;
+
+    fn accesses(_access: &mut AccessMap) {
+        // Nothing is touched until the system is done, at which point nothing else is running.
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        // Only `Scheduler` has one, which is how `ParallelScheduler` knows to turn these away.
+        required.push((
+            TypeId::of::<CommandQueue>(),
+            std::any::type_name::<CommandQueue>(),
+        ));
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        _state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        let cell = resources
+            .get(&TypeId::of::<CommandQueue>())
+            .expect("systems taking `Commands` must be added to a `Scheduler`");
+
+        // SAFETY:
+        // `CommandQueue` is private, so no system can ask for it mutably. The scheduler only
+        // touches it mutably through `&mut self`, when no system is running.
+        let queue = unsafe { &*cell.get() };
+
+        Ok(Commands {
+            queue: queue.downcast_ref().unwrap(),
+            // Added alongside the `CommandQueue`.
+            entities: Entities::get(resources).unwrap(),
+        })
+    }
+}
+
+impl Scheduler {
+    /// Applies every queued command, in the order they were queued, except for the ones held back
+    /// until a flush point. Takes the fields it needs rather than `&mut self` so it can be called
+    /// while the run loop is borrowing the rest.
+    fn apply_commands(resources: &mut TypeMap, resource_names: &mut HashMap<TypeId, &'static str>) {
+        let Some(queue) = resources.get_mut(&TypeId::of::<CommandQueue>()) else {
+            return;
+        };
+        let queue = queue.get_mut().downcast_mut::<CommandQueue>().unwrap();
+        let flushing = std::mem::take(queue.flushing.get_mut());
+        let (commands, held): (Vec<_>, Vec<_>) = std::mem::take(queue.commands.get_mut())
+            .into_iter()
+            .partition(|command| match command.kind() {
+                Some(kind) => !queue.held.contains(&kind) || flushing.contains(&kind),
+                None => true,
+            });
+        *queue.commands.get_mut() = held;
+
+        for command in commands {
+            match command {
+                Command::Insert(id, name, value) => {
+                    resources.insert(id, UnsafeCell::new(value));
+                    resource_names.insert(id, name);
+
+                    resources
+                        .entry(TypeId::of::<AccessCounts>())
+                        .or_insert_with(|| UnsafeCell::new(Box::new(AccessCounts::default())));
+                    ChangeTicks::mark_inserted(resources, id);
+                }
+                Command::Remove(id) => {
+                    resources.remove(&id);
+                    resource_names.remove(&id);
+                }
+                Command::RemovePlaceholder(id) => {
+                    // A held back `Insert` may have put a new value there since.
+                    let placeholder = resources
+                        .get_mut(&id)
+                        .is_some_and(|cell| cell.get_mut().is::<Moved>());
+                    if placeholder {
+                        resources.remove(&id);
+                        resource_names.remove(&id);
+                    }
+                }
+                Command::Spawn(entity, insert) => {
+                    let entities = Entities::get_mut(resources).unwrap();
+                    entities.alive.push(entity);
+                    insert(entities);
+                }
+                Command::Despawn(entity) => {
+                    Entities::get_mut(resources).unwrap().despawn(entity);
+                }
+            }
+        }
+    }
+}
+// ANCHOR_END: Commands
+
+// ANCHOR: DiscardOnPanic
+/// Throws away the commands a system queued if it panics partway through. Otherwise a caught panic
+/// would leave half a system's worth of changes behind, to be applied after whichever system runs
+/// next. The scheduler's own bookkeeping is kept, since it cleans up after things that did happen.
+struct DiscardOnPanic<'a> {
+    queue: &'a CommandQueue,
+    /// How many commands were already queued when the system started.
+    len: usize,
+}
+
+impl<'a> DiscardOnPanic<'a> {
+    fn new(resources: &'a TypeMap) -> Option<Self> {
+        let cell = resources.get(&TypeId::of::<CommandQueue>())?;
+
+        // SAFETY:
+        // `CommandQueue` is private, so no system can ask for it mutably. The scheduler only
+        // touches it mutably through `&mut self`, and not while this guard is alive.
+        let queue = unsafe { &*cell.get() };
+        let queue = queue.downcast_ref::<CommandQueue>().unwrap();
+
+        Some(DiscardOnPanic {
+            queue,
+            len: queue.commands.borrow().len(),
+        })
+    }
+}
+
+impl Drop for DiscardOnPanic<'_> {
+    fn drop(&mut self) {
+        // Destructors also run while unwinding, which is the only time there's anything to do.
+        if !std::thread::panicking() {
+            return;
+        }
+
+        if let Ok(mut commands) = self.queue.commands.try_borrow_mut() {
+            let mut index = 0;
+            commands.retain(|command| {
+                let keep = index < self.len || command.kind().is_none();
+                index += 1;
+                keep
+            });
+        }
+    }
+}
+// ANCHOR_END: DiscardOnPanic
+
+// ANCHOR: FlushPoint
+/// The kinds of command that can be held back until a [`FlushPoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum CommandKind {
+    /// Inserting and removing resources.
+    Resource,
+    /// Spawning and despawning entities.
+    Spawn,
+}
+
+/// A system that applies the held back commands of one kind. Added with
+/// [`Scheduler::add_flush_point`], and ordered like any other system.
+struct FlushPoint(CommandKind);
+
+impl System for FlushPoint {
+    type In = ();
+    type Out = ();
+
+    fn name(&self) -> Cow<'static, str> {
+        Cow::Owned(format!("FlushPoint({:?})", self.0))
+    }
+
+    fn accesses(&self, _accesses: &mut AccessMap) {
+        // Like `Commands`, nothing is touched until the system is done.
+    }
+
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+        Commands::requires(required);
+    }
+
+    fn try_run(&mut self, _input: (), resources: &TypeMap) -> Result<(), SystemParamError> {
+        let cell = resources
+            .get(&TypeId::of::<CommandQueue>())
+            .expect("flush points must be added with `Scheduler::add_flush_point`");
+
+        // SAFETY: See `Commands::retrieve`.
+        let queue = unsafe { &*cell.get() };
+        let queue = queue.downcast_ref::<CommandQueue>().unwrap();
+
+        // The scheduler applies commands after every system, this one included.
+        queue.flushing.borrow_mut().insert(self.0);
+        Ok(())
+    }
+}
+
+impl Scheduler {
+    /// Holds back every command of the flush point's kind until the flush point runs, rather than
+    /// applying it right after the system that queued it. Commands that are still held at the
+    /// end of a frame wait for the flush point in the next one.
+    pub fn add_flush_point<I>(
+        &mut self,
+        point: impl IntoSystem<I, System = FlushPoint>,
+    ) -> SystemId {
+        let descriptor = point.into_descriptor();
+        let kind = descriptor.system.0;
+        let id = self.push_system(descriptor.boxed());
+
+        // `push_system` made sure the queue exists.
+        let queue = self
+            .resources
+            .get_mut(&TypeId::of::<CommandQueue>())
+            .unwrap()
+            .get_mut()
+            .downcast_mut::<CommandQueue>()
+            .unwrap();
+        queue.held.insert(kind);
+        id
+    }
+}
+// ANCHOR_END: FlushPoint
+
+// ANCHOR: ResOwned
+/// Takes a resource out of the scheduler for good, handing the system ownership of it.
+struct ResOwned<T: 'static> {
+    value: T,
+}
+
+impl<T: 'static> ResOwned<T> {
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: 'static> Deref for ResOwned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: 'static> DerefMut for ResOwned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// What's left in the `TypeMap` after a `ResOwned` moves a resource out, until the removal is
+/// applied.
+struct Moved;
+
+impl Moved {
+    /// The cell holding `T`, unless there isn't one or a `ResOwned` has already taken the value
+    /// out of it. Parameters and the scheduler's own resource methods look resources up through
+    /// this, so that a placeholder looks just like a missing resource. That includes one left
+    /// behind by a system that panicked, which stays until the next run cleans it up.
+    fn cell<T: ?Sized + 'static>(resources: &TypeMap) -> Option<&UnsafeCell<Box<dyn Any>>> {
+        let cell = resources.get(&TypeId::of::<T>())?;
+
+        // SAFETY:
+        // Only called from `retrieve` by parameters that have recorded an access to `T`, or by
+        // the scheduler while no system is running, so nothing can be mutating the value while
+        // we check its type. The reference doesn't outlive this function.
+        let moved = unsafe { &*cell.get() }.is::<Moved>();
+
+        (!moved).then_some(cell)
+    }
+}
+
+impl<T: 'static> SystemParam for ResOwned<T> {
+    type State = ();
+    type Item<'new> = ResOwned<T>;
+
+    fn accesses(access: &mut AccessMap) {
+        // Taking the value is at least as exclusive as writing to it.
+        ResMut::<T>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        required.push((TypeId::of::<T>(), std::any::type_name::<T>()));
+        // For cleaning up afterwards.
+        Commands::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        // Got hold of before anything is moved, so that there's no failing halfway through.
+        // SAFETY: `Commands` doesn't access any resources.
+        let commands = unsafe { Commands::retrieve(resources, state) }?;
+
+        let value = Moved::cell::<T>(resources)
+            .ok_or_else(SystemParamError::missing::<T>)?
+            .get();
+
+        // SAFETY:
+        // The caller asserts that there are no conflicting accesses, and we recorded a write, so
+        // nothing else can be looking at the value while we swap it out.
+        let value = unsafe { &mut *value };
+
+        assert!(
+            !value.is::<Arc<T>>(),
+            "resource {} was added with `add_shared_resource`, so it can't be taken",
+            std::any::type_name::<T>(),
+        );
+        let value = std::mem::replace(value, Box::new(Moved));
+        let value = value.downcast::<T>().unwrap();
+        AccessCounts::record::<T>(resources);
+
+        // The map can't be changed from here, so get rid of the placeholder afterwards.
+        commands
+            .queue
+            .commands
+            .borrow_mut()
+            .push(Command::RemovePlaceholder(TypeId::of::<T>()));
+
+        Ok(ResOwned { value: *value })
+    }
+}
+// ANCHOR_END: ResOwned
+
+// ANCHOR: NonSend
+/// Shared access to a resource that must stay on the thread that created the scheduler, added
+/// with [`Scheduler::add_non_send_resource`].
+struct NonSend<'a, T: 'static> {
+    value: &'a T,
+    _guard: BorrowGuard<'a>,
+}
+
+/// Like [`NonSend`], but exclusive.
+struct NonSendMut<'a, T: 'static> {
+    value: &'a mut T,
+    _guard: BorrowGuard<'a>,
+}
+
+impl<T: 'static> Deref for NonSend<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: 'static> Deref for NonSendMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: 'static> DerefMut for NonSendMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'res, T: 'static> SystemParam for NonSend<'res, T> {
+    type State = ();
+    type Item<'new> = NonSend<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        Res::<T>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        Res::<T>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        MainThread::check::<T>(resources);
+
+        // SAFETY: The caller upholds the same contract for us as for `Res<T>`.
+        let res = unsafe { Res::<T>::retrieve(resources, state) }?;
+        Ok(NonSend {
+            value: res.value,
+            _guard: res._guard,
+        })
+    }
+}
+
+impl<'res, T: 'static> SystemParam for NonSendMut<'res, T> {
+    type State = ();
+    type Item<'new> = NonSendMut<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        ResMut::<T>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        ResMut::<T>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        MainThread::check::<T>(resources);
+
+        // SAFETY: The caller upholds the same contract for us as for `ResMut<T>`.
+        let res = unsafe { ResMut::<T>::retrieve(resources, state) }?;
+        Ok(NonSendMut {
+            value: res.value,
+            _guard: res._guard,
+        })
+    }
+}
+
+/// The thread the scheduler was created on. Once non-send resources are added, a copy lives in
+/// the `TypeMap` too, so that `retrieve` can check against it.
+#[derive(Clone, Copy)]
+struct MainThread(ThreadId);
+
+impl Default for MainThread {
+    fn default() -> Self {
+        MainThread(std::thread::current().id())
+    }
+}
+
+impl MainThread {
+    fn check<T>(resources: &TypeMap) {
+        let Some(cell) = resources.get(&TypeId::of::<MainThread>()) else {
+            return;
+        };
+
+        // SAFETY:
+        // `MainThread` is private, so no system can ask for it mutably. The scheduler only
+        // inserts it through `&mut self`.
+        let main_thread = unsafe { &*cell.get() };
+        let main_thread = main_thread.downcast_ref::<MainThread>().unwrap();
+
+        assert_eq!(
+            std::thread::current().id(),
+            main_thread.0,
+            "non-send resource {} accessed from a thread other than the scheduler's main thread",
+            std::any::type_name::<T>(),
+        );
+    }
+}
+
+impl Scheduler {
+    /// Adds a resource that isn't `Send`, such as a graphics context, for systems to access with
+    /// `NonSend<T>` and `NonSendMut<T>`. Those panic if they're ever retrieved on a thread other
+    /// than the one that created the scheduler.
+    pub fn add_non_send_resource<R: 'static>(&mut self, res: R) -> Option<R> {
+        let main_thread = self.main_thread;
+        self.resources
+            .entry(TypeId::of::<MainThread>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(main_thread)));
+
+        self.add_resource(res)
+    }
+}
+// ANCHOR_END: NonSend
+
+// ANCHOR: Res
+struct Res<'a, T: 'static> {
+    value: &'a T,
+    /// The `Arc` that `value` points into, for resources added with
+    /// [`Scheduler::add_shared_resource`].
+    shared: Option<&'a Arc<T>>,
+    _guard: BorrowGuard<'a>,
+}
+
+impl<T: 'static> Deref for Res<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+// ANCHOR_END: Res
+
+// ANCHOR: SharedResource
+impl<T: 'static> Res<'_, T> {
+    /// Another handle to the resource that can outlive the system, such as in a worker thread.
+    /// Only resources added with [`Scheduler::add_shared_resource`] have one.
+    pub fn to_arc(&self) -> Option<Arc<T>> {
+        self.shared.cloned()
+    }
+}
+
+impl Scheduler {
+    /// Adds `res` behind an `Arc`, so systems can hand it to other threads with [`Res::to_arc`]
+    /// instead of copying it. `Res<T>` reads it like any other resource, but it can't be
+    /// changed anymore: `ResMut<T>` panics, and [`Scheduler::get_resource_mut`] returns `None`.
+    pub fn add_shared_resource<T: Send + Sync + 'static>(&mut self, res: T) {
+        self.insert_resource_cell(
+            TypeId::of::<T>(),
+            std::any::type_name::<T>(),
+            Box::new(Arc::new(res)),
+        );
+    }
+}
+
+/// Unpacks a resource that was taken out of the `TypeMap`, which holds either a `T` or, for shared
+/// resources, an `Arc<T>`. The latter only gives up its value if it's the last handle.
+fn unbox_resource<T: 'static>(value: Box<dyn Any>) -> Option<T> {
+    match value.downcast::<T>() {
+        Ok(value) => Some(*value),
+        Err(value) => {
+            let shared = value.downcast::<Arc<T>>().ok()?;
+            Arc::try_unwrap(*shared).ok()
+        }
+    }
+}
+// ANCHOR_END: SharedResource
+
+// ANCHOR: ResMut
+struct ResMut<'a, T: 'static> {
+    value: &'a mut T,
+    /// Where to record that the resource was changed, if the scheduler keeps track.
+    ticks: Option<&'a ChangeTicks>,
+    _guard: BorrowGuard<'a>,
+}
+
+impl<T: 'static> Deref for ResMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: 'static> DerefMut for ResMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        if let Some(ticks) = self.ticks {
+            ticks.mark(TypeId::of::<T>());
+        }
+        self.value
+    }
+}
+// ANCHOR_END: ResMut
+
+// ANCHOR: ResDyn
+/// Shared access to a trait object resource added with [`Scheduler::add_trait_object_resource`].
+struct ResDyn<'a, T: ?Sized + 'static> {
+    value: &'a T,
+    _guard: BorrowGuard<'a>,
+}
+
+impl<T: ?Sized + 'static> Deref for ResDyn<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'res, T: ?Sized + 'static> SystemParam for ResDyn<'res, T> {
+    type State = ();
+    type Item<'new> = ResDyn<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        assert_eq!(
+            *access.entry(AccessKey::resource::<T>()).or_insert(Access::Read),
+            Access::Read,
+            "conflicting access in system; attempting to access {} mutably and immutably at the same
+            time",
+            std::any::type_name::<T>(),
+        );
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        required.push((TypeId::of::<T>(), std::any::type_name::<T>()));
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        _state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        let value = Moved::cell::<T>(resources)
+            .ok_or_else(SystemParamError::missing::<T>)?
+            .get();
+
+        // SAFETY:
+        // The caller asserts that there are no conflicting accesses, and the pointer is definitely
+        // valid as it was obtained directly from `UnsafeCell`. Its lifetime will be constrained
+        // to the lifetime of the map it was obtained from, so it cannot dangle.
+        let value = unsafe { &*value };
+
+        let value = value.downcast_ref::<Box<T>>().unwrap();
+        AccessCounts::record::<T>(resources);
+
+        Ok(ResDyn {
+            value,
+            _guard: BorrowGuard::new::<T>(resources),
+        })
+    }
+}
+
+impl Scheduler {
+    /// Adds a boxed trait object as a resource, keyed by the trait object type itself so that
+    /// systems can get at it with `ResDyn<dyn Trait>`.
+    ///
+    /// This is meant for unsized types like `dyn Trait`. For a sized `T` use `add_resource`;
+    /// a sized type added through here would clash with `Res<T>`, which expects an unboxed `T`.
+    pub fn add_trait_object_resource<T: ?Sized + 'static>(&mut self, value: Box<T>) {
+        self.insert_resource_cell(
+            TypeId::of::<T>(),
+            std::any::type_name::<T>(),
+            Box::new(value),
+        );
+    }
+}
+// ANCHOR_END: ResDyn
+
+// ANCHOR: AccessCounts
+/// How many times each resource has been retrieved by a system. Like `LiveBorrows`, it lives in
+/// the `TypeMap` itself so that `retrieve` can find it.
+#[derive(Default)]
+struct AccessCounts(RefCell<HashMap<TypeId, usize>>);
+
+impl AccessCounts {
+    fn get(resources: &TypeMap) -> Option<&AccessCounts> {
+        let cell = resources.get(&TypeId::of::<AccessCounts>())?;
+
+        // SAFETY:
+        // `AccessCounts` is private, so no system can ask for it mutably. The scheduler only
+        // inserts it through `&mut self`.
+        let counts = unsafe { &*cell.get() };
+
+        counts.downcast_ref::<AccessCounts>()
+    }
+
+    fn record<T: ?Sized + 'static>(resources: &TypeMap) {
+        if let Some(counts) = AccessCounts::get(resources) {
+            *counts.0.borrow_mut().entry(TypeId::of::<T>()).or_default() += 1;
+        }
+    }
+}
+
+impl Scheduler {
+    /// How many times each resource has been retrieved by a system so far, by name. Resources
+    /// that no system has touched are included with a count of zero, which makes them easy to
+    /// spot as candidates for removal.
+    pub fn resource_access_counts(&self) -> HashMap<&'static str, usize> {
+        let counts = AccessCounts::get(&self.resources).map(|counts| counts.0.borrow());
+
+        self.resource_names
+            .iter()
+            .map(|(id, name)| {
+                let count = counts
+                    .as_ref()
+                    .and_then(|counts| counts.get(id).copied())
+                    .unwrap_or(0);
+                (*name, count)
+            })
+            .collect()
+    }
+}
+// ANCHOR_END: AccessCounts
+
+// ANCHOR: ChangeTicks
+/// When each resource was last changed. Like `AccessCounts`, it lives in the `TypeMap` itself so
+/// that `retrieve` can find it.
+///
+/// Ticks count changes rather than frames: every change gets a tick of its own. That way a system
+/// can tell apart changes made after it ran from ones made before, even within the same frame.
+#[derive(Default)]
+struct ChangeTicks {
+    /// The tick of the most recent change to any resource.
+    tick: Cell<u64>,
+    changed: RefCell<HashMap<TypeId, u64>>,
+}
+
+impl ChangeTicks {
+    fn get(resources: &TypeMap) -> Option<&ChangeTicks> {
+        let cell = resources.get(&TypeId::of::<ChangeTicks>())?;
+
+        // SAFETY:
+        // `ChangeTicks` is private, so no system can ask for it mutably. The scheduler only
+        // inserts it through `&mut self`.
+        let ticks = unsafe { &*cell.get() };
+
+        ticks.downcast_ref::<ChangeTicks>()
+    }
+
+    /// Records a newly inserted resource, which counts as a change.
+    fn mark_inserted(resources: &mut TypeMap, id: TypeId) {
+        let cell = resources
+            .entry(TypeId::of::<ChangeTicks>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(ChangeTicks::default())));
+        cell.get_mut()
+            .downcast_ref::<ChangeTicks>()
+            .unwrap()
+            .mark(id);
+    }
+
+    fn mark(&self, id: TypeId) {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        self.changed.borrow_mut().insert(id, tick);
+    }
+
+    /// Whether the resource changed after `tick`.
+    fn changed_since(&self, id: TypeId, tick: u64) -> bool {
+        self.changed
+            .borrow()
+            .get(&id)
+            .is_some_and(|&changed| changed > tick)
+    }
+}
+
+/// Like `Res<T>`, but also knows whether the resource changed since the last time this system
+/// ran. A resource counts as changed when it's added, and whenever a `ResMut<T>` is dereferenced
+/// mutably, even if nothing actually ends up different.
+struct Changed<'a, T: 'static> {
+    res: Res<'a, T>,
+    changed: bool,
+}
+
+impl<T: 'static> Changed<'_, T> {
+    /// Always `true` the first time a system runs, since the resource was added at some point
+    /// before that.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+}
+
+impl<T: 'static> Deref for Changed<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.res
+    }
+}
+
+impl<'res, T: 'static> SystemParam for Changed<'res, T> {
+    /// The state for the `Res<T>`, the tick the system last ran at, and the tick it's running at
+    /// now, which becomes the former once the system is done.
+    type State = ((), u64, u64);
+    type Item<'new> = Changed<'new, T>;
+
+    fn accesses(access: &mut AccessMap) {
+        Res::<T>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        Res::<T>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        (state, last_run, running): &'r mut ((), u64, u64),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        // SAFETY: The caller upholds the same contract for us as for `Res<T>`.
+        let res = unsafe { Res::<T>::retrieve(resources, state) }?;
+
+        // Without anything keeping track, the safe answer is that it might have changed.
+        let changed = ChangeTicks::get(resources).is_none_or(|ticks| {
+            *running = ticks.tick.get();
+            ticks.changed_since(TypeId::of::<T>(), *last_run)
+        });
+
+        Ok(Changed { res, changed })
+    }
+
+    fn system_ran((_, last_run, running): &mut ((), u64, u64)) {
+        *last_run = *running;
+    }
+}
+// ANCHOR_END: ChangeTicks
+
+// ANCHOR: LiveBorrows
+/// Counts the resource borrows that are currently alive, by type name. The scheduler only stores
+/// one of these in the `TypeMap` while borrow tracking is turned on.
+#[derive(Default)]
+struct LiveBorrows(RefCell<HashMap<&'static str, usize>>);
+
+impl LiveBorrows {
+    fn get(resources: &TypeMap) -> Option<&LiveBorrows> {
+        let cell = resources.get(&TypeId::of::<LiveBorrows>())?;
+
+        // SAFETY:
+        // `LiveBorrows` is private, so no system can ask for it mutably. The scheduler only
+        // inserts or removes it through `&mut self`, while no guards can be alive.
+        let borrows = unsafe { &*cell.get() };
+
+        borrows.downcast_ref::<LiveBorrows>()
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.0.borrow().keys().copied().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Registers a borrow with the `LiveBorrows` when created, and deregisters it when dropped. Does
+/// nothing at all when borrow tracking is off.
+struct BorrowGuard<'a> {
+    borrows: Option<&'a LiveBorrows>,
+    name: &'static str,
+}
+
+impl<'a> BorrowGuard<'a> {
+    fn new<T: ?Sized + 'static>(resources: &'a TypeMap) -> Self {
+        let borrows = LiveBorrows::get(resources);
+        let name = std::any::type_name::<T>();
+        if let Some(borrows) = borrows {
+            *borrows.0.borrow_mut().entry(name).or_default() += 1;
+        }
+
+        BorrowGuard { borrows, name }
+    }
+}
+
+impl Drop for BorrowGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(borrows) = self.borrows {
+            let mut borrows = borrows.0.borrow_mut();
+            let count = borrows.get_mut(self.name).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                borrows.remove(self.name);
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    /// Turns borrow tracking on or off. While it's on, every `Res`/`ResMut` registers itself
+    /// until it is dropped, and `run` panics if a system leaves a borrow behind when it returns.
+    pub fn set_borrow_tracking(&mut self, enabled: bool) {
+        let id = TypeId::of::<LiveBorrows>();
+        if enabled {
+            self.resources
+                .entry(id)
+                .or_insert_with(|| UnsafeCell::new(Box::new(LiveBorrows::default())));
+        } else {
+            self.resources.remove(&id);
+        }
+    }
+
+    /// The names of the resources that currently have live borrows, or an empty list if borrow
+    /// tracking is off.
+    pub fn live_borrows(&self) -> Vec<&'static str> {
+        LiveBorrows::get(&self.resources)
+            .map(LiveBorrows::names)
+            .unwrap_or_default()
+    }
+}
+// ANCHOR_END: LiveBorrows
+
+struct FunctionSystem<Input, F> {
+    f: F,
+    /// The `State` of every parameter, as a tuple. It's boxed up because its type depends on the
+    /// parameters, which `Input` only spells out inside the `System` impls. Created on first run.
+    state: Option<Box<dyn Any + Send>>,
+    marker: PhantomData<fn() -> Input>,
+}
+
+// ANCHOR: System
+trait System {
+    /// The value piped into the system when it runs. `()` for most systems.
+    type In;
+    /// The value the system returns. Systems stored in the scheduler must return `()`.
+    type Out;
+
+    /// A human-readable name for the system, used when reporting on it.
+    fn name(&self) -> Cow<'static, str>;
+
+    /// Records the accesses this system's parameters will make, without running it.
+    fn accesses(&self, accesses: &mut AccessMap);
+
+    /// Records the resources this system can't run without, along with their type names.
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>);
+
+    /// Runs the system, or fails without running it if one of its parameters isn't available.
+    ///
+    /// Systems check that their parameters don't conflict when they're created, so there's no
+    /// need to check again on every run.
+    fn try_run(
+        &mut self,
+        input: Self::In,
+        resources: &TypeMap,
+    ) -> Result<Self::Out, SystemParamError>;
+
+    /// Like [`System::try_run`], but returns `None` if the system decided not to run, such as
+    /// when its run condition was false. Systems that always run can leave this alone.
+    fn try_run_or_skip(
+        &mut self,
+        input: Self::In,
+        resources: &TypeMap,
+    ) -> Result<Option<Self::Out>, SystemParamError> {
+        self.try_run(input, resources).map(Some)
+    }
+
+    /// Describes what decides whether the system runs, such as run conditions and features, for
+    /// [`Scheduler::debug_dump`]. Adds nothing for systems that always run. `resources` is only
+    /// looked at to show how things stand right now, such as whether a feature is switched off.
+    fn describe_conditions(&self, _resources: &TypeMap, _conditions: &mut Vec<String>) {}
+
+    /// Like [`System::try_run`], but panics if one of the system's parameters isn't available.
+    fn run(&mut self, input: Self::In, resources: &TypeMap) -> Self::Out {
+        self.try_run(input, resources)
+            .unwrap_or_else(|error| panic!("could not run system {}: {error}", self.name()))
+    }
+}
+// ANCHOR_END: System
+
+// ANCHOR: call_for_each_arity
+/// Invokes `$target` once for every length of the parameter list, from all of them down to none.
+/// Which names the parameters get doesn't matter, only how many there are.
+macro_rules! call_for_each_arity {
+    ($target:ident;) => {
+        $target!();
+    };
+
+    ($target:ident; $first:ident $(, $rest:ident)*) => {
+        $target!($first $(, $rest)*);
+        call_for_each_arity!($target; $($rest),*);
+    };
+}
+// ANCHOR_END: call_for_each_arity
+
+call_for_each_arity!(impl_system; T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15, T16);
+
+trait IntoSystem<Input> {
+    type System: System;
+
+    fn into_system(self) -> Self::System;
+
+    /// Feeds the output of this system into `next`, whose first parameter must be an
+    /// `In<Self::Out>`. Ordering constraints on either half apply to the pipe as a whole.
+    fn pipe<NextInput, Next>(
+        self,
+        next: Next,
+    ) -> SystemDescriptor<PipeSystem<Self::System, Next::System>>
+    where
+        Self: Sized,
+        Next: IntoSystem<NextInput>,
+        Next::System: System<In = <Self::System as System>::Out>,
+    {
+        let SystemDescriptor {
+            system: second,
+            before,
+            after,
+        } = next.into_descriptor();
+        let mut descriptor = self
+            .into_descriptor()
+            .map(|first| PipeSystem { first, second });
+        descriptor.before.extend(before);
+        descriptor.after.extend(after);
+        descriptor
+    }
+
+    /// Skips this system on frames where the [`Paused`] resource is `Paused(true)`.
+    fn when_unpaused(self) -> SystemDescriptor<WhenUnpaused<Self::System>>
+    where
+        Self: Sized,
+    {
+        self.into_descriptor().map(|system| WhenUnpaused { system })
+    }
+
+    /// Puts this system in the named feature group, so it can be switched on and off along with
+    /// the rest of the group using [`Scheduler::set_feature_enabled`].
+    fn in_feature(self, feature: &'static str) -> SystemDescriptor<InFeature<Self::System>>
+    where
+        Self: Sized,
+    {
+        self.into_descriptor()
+            .map(|system| InFeature { system, feature })
+    }
+
+    /// Only runs this system on frames where `condition`, a system returning `bool`, returns
+    /// `true`. The condition runs first, every frame, and its parameters count towards this
+    /// system's accesses.
+    fn run_if<ConditionInput, Condition>(
+        self,
+        condition: Condition,
+    ) -> SystemDescriptor<RunIf<Condition::System, Self::System>>
+    where
+        Self: Sized,
+        Condition: IntoSystem<ConditionInput>,
+        Condition::System: System<In = (), Out = bool>,
+    {
+        let condition = condition.into_system();
+        self.into_descriptor()
+            .map(|system| RunIf { condition, system })
+    }
+
+    /// The system along with any ordering constraints it was given. Only a [`SystemDescriptor`]
+    /// has any; everything else starts out without.
+    fn into_descriptor(self) -> SystemDescriptor<Self::System>
+    where
+        Self: Sized,
+    {
+        SystemDescriptor::new(self.into_system())
+    }
+
+    /// Makes this system run before `other` in every frame.
+    fn before<OtherInput>(
+        self,
+        other: impl IntoSystem<OtherInput>,
+    ) -> SystemDescriptor<Self::System>
+    where
+        Self: Sized,
+    {
+        let mut descriptor = self.into_descriptor();
+        descriptor.before.push(other.into_system().name());
+        descriptor
+    }
+
+    /// Makes this system run after `other` in every frame.
+    fn after<OtherInput>(self, other: impl IntoSystem<OtherInput>) -> SystemDescriptor<Self::System>
+    where
+        Self: Sized,
+    {
+        let mut descriptor = self.into_descriptor();
+        descriptor.after.push(other.into_system().name());
+        descriptor
+    }
+}
+
+call_for_each_arity!(impl_into_system; T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15, T16);
+
+// ANCHOR: IntoSystemTuple
+/// A tuple of things that are each [`IntoSystem`], for adding several systems in one go with
+/// [`Scheduler::add_systems`].
+trait IntoSystemTuple<Input> {
+    fn into_systems(self) -> Vec<SystemDescriptor<BoxedSystem>>;
+}
+
+macro_rules! impl_into_system_tuple {
+    (
+        $(($systems:ident, $inputs:ident)),*
+    ) => {
+        impl<$($systems, $inputs),*> IntoSystemTuple<($($inputs,)*)> for ($($systems,)*)
+            where
+                $(
+                    $systems: IntoSystem<$inputs>,
+                    $systems::System: System<In = (), Out = ()> + 'static,
+                )*
+        {
+            #[allow(non_snake_case)]
+            fn into_systems(self) -> Vec<SystemDescriptor<BoxedSystem>> {
+                let ($($systems,)*) = self;
+                vec![$($systems.into_descriptor().boxed()),*]
+            }
+        }
+    }
+}
+
+impl_into_system_tuple!((S1, I1));
+impl_into_system_tuple!((S1, I1), (S2, I2));
+impl_into_system_tuple!((S1, I1), (S2, I2), (S3, I3));
+impl_into_system_tuple!((S1, I1), (S2, I2), (S3, I3), (S4, I4));
+impl_into_system_tuple!((S1, I1), (S2, I2), (S3, I3), (S4, I4), (S5, I5));
+impl_into_system_tuple!((S1, I1), (S2, I2), (S3, I3), (S4, I4), (S5, I5), (S6, I6));
+impl_into_system_tuple!(
+    (S1, I1),
+    (S2, I2),
+    (S3, I3),
+    (S4, I4),
+    (S5, I5),
+    (S6, I6),
+    (S7, I7)
+);
+impl_into_system_tuple!(
+    (S1, I1),
+    (S2, I2),
+    (S3, I3),
+    (S4, I4),
+    (S5, I5),
+    (S6, I6),
+    (S7, I7),
+    (S8, I8)
+);
+// ANCHOR_END: IntoSystemTuple
+
+// ANCHOR: Bound
+/// Some state bundled with a method-like function that takes `&mut` that state as its first
+/// argument. Created with [`bind`].
+struct Bound<T, F> {
+    state: T,
+    f: F,
+}
+
+fn bind<T: 'static, F>(state: T, f: F) -> Bound<T, F> {
+    Bound { state, f }
+}
+
+struct BoundSystem<Input, T, F> {
+    state: T,
+    f: F,
+    /// Like `FunctionSystem::state`.
+    param_state: Option<Box<dyn Any + Send>>,
+    marker: PhantomData<fn() -> Input>,
+}
+// ANCHOR_END: Bound
+
+// ANCHOR: impl_bound_system_macro
+macro_rules! impl_bound_system {
+    (
+        $($params:ident),*
+    ) => {
+        #[allow(non_snake_case)]
+        #[allow(unused)]
+        impl<T: 'static, F, $($params: SystemParam),*> System for BoundSystem<($($params,)*), T, F>
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( &mut T, $($params),* ) +
+                    FnMut( &mut T, $(<$params as SystemParam>::Item<'b>),* )
+        {
+            type In = ();
+            type Out = ();
+
+            fn name(&self) -> Cow<'static, str> {
+                Cow::Borrowed(std::any::type_name::<F>())
+            }
+
+            fn accesses(&self, accesses: &mut AccessMap) {
+                $(
+                    $params::accesses(accesses);
+                )*
+            }
+
+            fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+                $(
+                    $params::requires(required);
+                )*
+            }
+
+            fn try_run(
+                &mut self,
+                _input: (),
+                resources: &TypeMap,
+            ) -> Result<(), SystemParamError> {
+                #[allow(clippy::too_many_arguments)]
+                fn call_inner<T, $($params),*>(
+                    mut f: impl FnMut(&mut T, $($params),*),
+                    state: &mut T,
+                    $($params: $params),*
+                ) {
+                    f(state, $($params),*)
+                }
+
+                // SAFETY:
+                // Every access here is proven to be nonconflicting because `into_system` called
+                // `accesses` for every parameter, which would have panicked otherwise. `state` is
+                // owned by the system, so it can't alias any resource.
+                let param_state = self.param_state.get_or_insert_with(|| {
+                    Box::new(($(<$params as SystemParam>::State::default(),)*))
+                });
+                let states = param_state
+                    .downcast_mut::<($(<$params as SystemParam>::State,)*)>()
+                    .unwrap();
+                {
+                    let ($($params,)*) = &mut *states;
+                    $(
+                        let $params = unsafe { $params::retrieve(resources, $params) }?;
+                    )*
+                    call_inner(&mut self.f, &mut self.state, $($params),*);
+                }
+
+                let ($($params,)*) = states;
+                $(
+                    $params::system_ran($params);
+                )*
+                Ok(())
+            }
+        }
+
+        // The input is spelled as a function pointer type so that it can never overlap with the
+        // tuple inputs of the plain function impls.
+        impl<T: 'static, F, $($params: SystemParam),*> IntoSystem<fn(&mut T, $($params),*)> for Bound<T, F>
+            where
+                for<'a, 'b> &'a mut F:
+                    FnMut( &mut T, $($params),* ) +
+                    FnMut( &mut T, $(<$params as SystemParam>::Item<'b>),* )
+        {
+            type System = BoundSystem<($($params,)*), T, F>;
+
+            fn into_system(self) -> Self::System {
+                let system = BoundSystem {
+                    state: self.state,
+                    f: self.f,
+                    param_state: None,
+                    marker: Default::default(),
+                };
+                // Check for conflicting parameters once, here, so that running doesn't have to.
+                system.accesses(&mut AccessMap::new());
+                system
+            }
+        }
+    }
+}
+// ANCHOR_END: impl_bound_system_macro
+
+call_for_each_arity!(impl_bound_system; T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15, T16);
+
+// ANCHOR: AlreadySystem
+/// The `IntoSystem` input for values that are already systems, such as a `PipeSystem`.
+struct AlreadySystem;
+
+impl<S: System> IntoSystem<AlreadySystem> for S {
+    type System = S;
+
+    fn into_system(self) -> S {
+        self
+    }
+}
+// ANCHOR_END: AlreadySystem
+
+// ANCHOR: SystemDescriptor
+/// A system along with the constraints on when it runs relative to other systems, which are
+/// named the same way [`System::name`] does.
+struct SystemDescriptor<S> {
+    system: S,
+    before: Vec<Cow<'static, str>>,
+    after: Vec<Cow<'static, str>>,
+}
+
+impl<S> SystemDescriptor<S> {
+    fn new(system: S) -> Self {
+        SystemDescriptor {
+            system,
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    /// Wraps the system in something else, such as a run condition, keeping its constraints.
+    fn map<T>(self, f: impl FnOnce(S) -> T) -> SystemDescriptor<T> {
+        SystemDescriptor {
+            system: f(self.system),
+            before: self.before,
+            after: self.after,
+        }
+    }
+
+    fn boxed(self) -> SystemDescriptor<BoxedSystem>
+    where
+        S: System<In = (), Out = ()> + 'static,
+    {
+        self.map(|system| Box::new(system) as BoxedSystem)
+    }
+}
+
+/// The `IntoSystem` input for a [`SystemDescriptor`], so that constraints can be chained.
+struct DescribedSystem;
+
+impl<S: System> IntoSystem<DescribedSystem> for SystemDescriptor<S> {
+    type System = S;
+
+    fn into_system(self) -> S {
+        self.system
+    }
+
+    fn into_descriptor(self) -> Self {
+        self
+    }
+}
+// ANCHOR_END: SystemDescriptor
+
+// ANCHOR: Pipe
+/// A value piped into a system from the one before it. Must be the system's first parameter.
+struct In<T>(T);
+
+struct PipeSystem<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: System, B: System<In = A::Out>> System for PipeSystem<A, B> {
+    type In = A::In;
+    type Out = B::Out;
+
+    fn name(&self) -> Cow<'static, str> {
+        Cow::Owned(format!("{} | {}", self.first.name(), self.second.name()))
+    }
+
+    fn accesses(&self, accesses: &mut AccessMap) {
+        self.first.accesses(accesses);
+
+        // The two halves never run at the same time, so they can't conflict with each other.
+        let mut second = AccessMap::new();
+        self.second.accesses(&mut second);
+        merge_accesses(accesses, second);
+    }
+
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+        self.first.requires(required);
+        self.second.requires(required);
+    }
+
+    fn try_run(&mut self, input: A::In, resources: &TypeMap) -> Result<B::Out, SystemParamError> {
+        // `first` has returned before `second` starts, so none of its parameters are alive
+        // anymore, and `second` is free to access the same resources.
+        let value = self.first.try_run(input, resources)?;
+        self.second.try_run(value, resources)
+    }
+
+    fn try_run_or_skip(
+        &mut self,
+        input: A::In,
+        resources: &TypeMap,
+    ) -> Result<Option<B::Out>, SystemParamError> {
+        // Without a value from `first`, there's nothing to run `second` with.
+        match self.first.try_run_or_skip(input, resources)? {
+            Some(value) => self.second.try_run_or_skip(value, resources),
+            None => Ok(None),
+        }
+    }
+
+    fn describe_conditions(&self, resources: &TypeMap, conditions: &mut Vec<String>) {
+        self.first.describe_conditions(resources, conditions);
+        self.second.describe_conditions(resources, conditions);
+    }
+}
+
+/// Adds the accesses of something that never runs at the same time as whatever recorded
+/// `accesses`, so the two can't conflict. The result accesses whatever either does, with writes
+/// winning over reads.
+fn merge_accesses(accesses: &mut AccessMap, other: AccessMap) {
+    for (id, access) in other {
+        let entry = accesses.entry(id).or_insert(access);
+        if access == Access::Write {
+            *entry = Access::Write;
+        }
+    }
+}
+// ANCHOR_END: Pipe
+
+// ANCHOR: RunIf
+/// Runs `system` only when `condition` returns `true`. Created with [`IntoSystem::run_if`].
+struct RunIf<C, S> {
+    condition: C,
+    system: S,
+}
+
+impl<C: System<In = (), Out = bool>, S: System<In = (), Out = ()>> System for RunIf<C, S> {
+    type In = ();
+    type Out = ();
+
+    fn name(&self) -> Cow<'static, str> {
+        self.system.name()
+    }
+
+    fn accesses(&self, accesses: &mut AccessMap) {
+        self.system.accesses(accesses);
+
+        // The condition has returned before the system starts, like the two halves of a pipe.
+        let mut condition = AccessMap::new();
+        self.condition.accesses(&mut condition);
+        merge_accesses(accesses, condition);
+    }
+
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+        self.condition.requires(required);
+        self.system.requires(required);
+    }
+
+    fn try_run(&mut self, input: (), resources: &TypeMap) -> Result<(), SystemParamError> {
+        self.try_run_or_skip(input, resources).map(drop)
+    }
+
+    fn try_run_or_skip(
+        &mut self,
+        _input: (),
+        resources: &TypeMap,
+    ) -> Result<Option<()>, SystemParamError> {
+        if self.condition.try_run((), resources)? {
+            self.system.try_run_or_skip((), resources)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn describe_conditions(&self, resources: &TypeMap, conditions: &mut Vec<String>) {
+        conditions.push(format!("run if {}", self.condition.name()));
+        self.system.describe_conditions(resources, conditions);
+    }
+}
+
+/// A run condition that's true while a resource of type `T` exists.
+fn resource_exists<T: 'static>(res: Option<Res<T>>) -> bool {
+    res.is_some()
+}
+
+/// A run condition that's true while a resource of type `T` exists and is equal to `value`.
+fn resource_equals<T: PartialEq + 'static>(value: T) -> impl FnMut(Option<Res<'_, T>>) -> bool {
+    move |res| res.is_some_and(|res| *res == value)
+}
+// ANCHOR_END: RunIf
+
+// ANCHOR: Paused
+/// While this resource is `Paused(true)`, systems added with `.when_unpaused()` are skipped. All
+/// other systems keep running, so menus and input handling still work.
+struct Paused(bool);
+
+impl Paused {
+    /// Whether there is a `Paused(true)` resource. A missing `Paused` counts as not paused.
+    ///
+    /// # Safety
+    /// Nothing may be holding a `ResMut<Paused>`.
+    unsafe fn is_set(resources: &TypeMap) -> bool {
+        resources.get(&TypeId::of::<Paused>()).is_some_and(|cell| {
+            // SAFETY:
+            // The caller promises there's no `ResMut<Paused>` around. The reference doesn't
+            // outlive this closure.
+            let paused = unsafe { &*cell.get() };
+            paused.downcast_ref::<Paused>().unwrap().0
+        })
+    }
+}
+
+struct WhenUnpaused<S> {
+    system: S,
+}
+
+impl<S: System<In = (), Out = ()>> System for WhenUnpaused<S> {
+    type In = ();
+    type Out = ();
+
+    fn name(&self) -> Cow<'static, str> {
+        self.system.name()
+    }
+
+    fn accesses(&self, accesses: &mut AccessMap) {
+        self.system.accesses(accesses);
+        accesses
+            .entry(AccessKey::resource::<Paused>())
+            .or_insert(Access::Read);
+    }
+
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+        // A missing `Paused` counts as not paused, so it isn't required.
+        self.system.requires(required);
+    }
+
+    fn try_run(&mut self, input: (), resources: &TypeMap) -> Result<(), SystemParamError> {
+        self.try_run_or_skip(input, resources).map(drop)
+    }
+
+    fn try_run_or_skip(
+        &mut self,
+        _input: (),
+        resources: &TypeMap,
+    ) -> Result<Option<()>, SystemParamError> {
+        // SAFETY:
+        // Under the single-threaded `Scheduler`, systems run one at a time and none is running
+        // right now, so nothing can be holding a `ResMut<Paused>`. That isn't true of
+        // `ParallelScheduler`, where other systems are running alongside this one. There it's
+        // `accesses` that keeps us safe: it declares a read of `Paused`, so no system that writes
+        // it is ever put in the same batch.
+        let paused = unsafe { Paused::is_set(resources) };
+
+        if paused {
+            Ok(None)
+        } else {
+            self.system.try_run_or_skip((), resources)
+        }
+    }
+
+    fn describe_conditions(&self, resources: &TypeMap, conditions: &mut Vec<String>) {
+        // SAFETY:
+        // Only `Scheduler::debug_dump` asks for a description, and it holds `&Scheduler`, so no
+        // system is running.
+        if unsafe { Paused::is_set(resources) } {
+            conditions.push("when unpaused (paused right now)".to_owned());
+        } else {
+            conditions.push("when unpaused".to_owned());
+        }
+        self.system.describe_conditions(resources, conditions);
+    }
+}
+// ANCHOR_END: Paused
+
+// ANCHOR: Features
+/// The feature groups that are switched off. Only the scheduler can change it, through
+/// [`Scheduler::set_feature_enabled`].
+#[derive(Default)]
+struct DisabledFeatures(HashSet<String>);
+
+impl DisabledFeatures {
+    /// Whether `feature` has been switched off.
+    fn contains(resources: &TypeMap, feature: &str) -> bool {
+        resources
+            .get(&TypeId::of::<DisabledFeatures>())
+            .is_some_and(|cell| {
+                // SAFETY:
+                // `DisabledFeatures` is only ever changed through `&mut Scheduler`, which can't
+                // happen while `resources` is borrowed. The reference doesn't outlive this
+                // closure.
+                let disabled = unsafe { &*cell.get() };
+                let disabled = disabled.downcast_ref::<DisabledFeatures>().unwrap();
+                disabled.0.contains(feature)
+            })
+    }
+}
+
+struct InFeature<S> {
+    system: S,
+    feature: &'static str,
+}
+
+impl<S: System<In = (), Out = ()>> System for InFeature<S> {
+    type In = ();
+    type Out = ();
+
+    fn name(&self) -> Cow<'static, str> {
+        self.system.name()
+    }
+
+    fn accesses(&self, accesses: &mut AccessMap) {
+        // Nothing outside this file can name `DisabledFeatures`, so there's nothing for the
+        // check below to conflict with.
+        self.system.accesses(accesses);
+    }
+
+    fn requires(&self, required: &mut Vec<(TypeId, &'static str)>) {
+        self.system.requires(required);
+    }
+
+    fn try_run(&mut self, input: (), resources: &TypeMap) -> Result<(), SystemParamError> {
+        self.try_run_or_skip(input, resources).map(drop)
+    }
+
+    fn try_run_or_skip(
+        &mut self,
+        _input: (),
+        resources: &TypeMap,
+    ) -> Result<Option<()>, SystemParamError> {
+        if DisabledFeatures::contains(resources, self.feature) {
+            Ok(None)
+        } else {
+            self.system.try_run_or_skip((), resources)
+        }
+    }
+
+    fn describe_conditions(&self, resources: &TypeMap, conditions: &mut Vec<String>) {
+        if DisabledFeatures::contains(resources, self.feature) {
+            conditions.push(format!("in feature {:?} (disabled)", self.feature));
+        } else {
+            conditions.push(format!("in feature {:?}", self.feature));
+        }
+        self.system.describe_conditions(resources, conditions);
+    }
+}
+
+impl Scheduler {
+    /// Switches every system added with `.in_feature(feature)` on or off. Features are on until
+    /// they're switched off, including ones that no system belongs to yet.
+    pub fn set_feature_enabled(&mut self, feature: &str, enabled: bool) {
+        let cell = self
+            .resources
+            .entry(TypeId::of::<DisabledFeatures>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(DisabledFeatures::default())));
+        let disabled = cell.get_mut().downcast_mut::<DisabledFeatures>().unwrap();
+
+        if enabled {
+            disabled.0.remove(feature);
+        } else {
+            disabled.0.insert(feature.to_owned());
+        }
+    }
+}
+// ANCHOR_END: Features
+
+// ANCHOR: StoredSystem
+type BoxedSystem = Box<dyn System<In = (), Out = ()>>;
+
+struct StoredSystem {
+    system: BoxedSystem,
+    /// Everything the system accesses, worked out once when it was added.
+    accesses: AccessMap,
+    /// Names of the systems this one has to run before.
+    before: Vec<Cow<'static, str>>,
+    /// Names of the systems this one has to run after.
+    after: Vec<Cow<'static, str>>,
+}
+// ANCHOR_END: StoredSystem
+
+// ANCHOR: Platform
+/// The platforms [`Scheduler::add_system_for_platform`] can tell apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Platform {
+    Windows,
+    MacOs,
+    Linux,
+    Web,
+    /// Anything not listed above.
+    Other,
+}
+
+impl Platform {
+    /// The platform this program is running on.
+    pub fn current() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            Platform::Web
+        } else if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else if cfg!(target_os = "linux") {
+            Platform::Linux
+        } else {
+            Platform::Other
+        }
+    }
+}
+
+impl Scheduler {
+    /// Adds `res` only if `condition` is true, for resources that depend on what the app finds
+    /// at startup, like a gamepad or a GPU feature. Returns whether it was added. Systems that
+    /// need the resource can check for it with `.run_if(resource_exists::<R>)`.
+    pub fn add_resource_if<R: 'static>(&mut self, condition: bool, res: R) -> bool {
+        if condition {
+            self.add_resource(res);
+        }
+        condition
+    }
+
+    /// Adds `system` only when running on `platform`, and returns its id if it was added.
+    pub fn add_system_for_platform<I, S: System<In = (), Out = ()> + 'static>(
+        &mut self,
+        platform: Platform,
+        system: impl IntoSystem<I, System = S>,
+    ) -> Option<SystemId> {
+        (platform == Platform::current()).then(|| self.add_system(system))
+    }
+}
+// ANCHOR_END: Platform
+
+// ANCHOR: Stage
+/// When a system runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// Once, at the start of the first run, before anything else.
+    Startup,
+    /// Every run. This is where [`Scheduler::add_system`] puts systems.
+    Update,
+}
+
+impl Scheduler {
+    /// Adds a system to the given stage. Startup systems have to be added before the first run.
+    /// They keep to their `.before()` and `.after()` constraints among themselves, and otherwise
+    /// run in the order they were added.
+    pub fn add_system_to_stage<I, S: System<In = (), Out = ()> + 'static>(
+        &mut self,
+        stage: Stage,
+        system: impl IntoSystem<I, System = S>,
+    ) {
+        match stage {
+            Stage::Startup => {
+                let stored = self.store_system(system.into_descriptor().boxed());
+                assert!(
+                    !self.already_ran_startup,
+                    "startup has already run, so startup system {} never would",
+                    stored.system.name(),
+                );
+                self.startup_systems.push(stored);
+            }
+            Stage::Update => {
+                self.add_system(system);
+            }
+        }
+    }
+
+    /// Runs the startup systems, if they haven't run yet. A startup system that fails stays
+    /// around, along with the ones after it, to be tried again on the next run.
+    fn run_startup(&mut self) -> Result<(), SystemParamError> {
+        if self.already_ran_startup {
+            return Ok(());
+        }
+
+        let order = order_systems(&self.startup_systems, &[], false);
+        let mut unordered: Vec<_> = self.startup_systems.drain(..).map(Some).collect();
+        self.startup_systems = order
+            .into_iter()
+            .map(|id| unordered[id.0].take().unwrap())
+            .collect();
+
+        while !self.startup_systems.is_empty() {
+            Scheduler::run_system(
+                &mut self.startup_systems[0].system,
+                &mut self.resources,
+                &mut self.resource_names,
+                self.watchdog.as_ref(),
+            )?;
+            // Startup systems only ever run once, so there's no reason to keep them around.
+            self.startup_systems.remove(0);
+        }
+
+        self.already_ran_startup = true;
+        Ok(())
+    }
+}
+// ANCHOR_END: Stage
+
+// ANCHOR: SystemId
+/// Identifies a system within the scheduler it was added to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct SystemId(usize);
+// ANCHOR_END: SystemId
+
+// ANCHOR: Scheduler
+#[derive(Default)]
+struct Scheduler {
+    systems: Vec<StoredSystem>,
+    /// Systems in [`Stage::Startup`] that haven't run yet.
+    startup_systems: Vec<StoredSystem>,
+    already_ran_startup: bool,
+    resources: TypeMap,
+    resource_names: HashMap<TypeId, &'static str>,
+    deferred: Vec<SystemId>,
+    pre_frame: Option<FrameHook>,
+    post_frame: Option<FrameHook>,
+    watchdog: Option<Watchdog>,
+    auto_order: bool,
+    time_mode: TimeMode,
+    main_thread: MainThread,
+    /// One function per event type added with [`Scheduler::add_event`], which updates its
+    /// [`Events`] resource.
+    event_updaters: Vec<fn(&mut TypeMap)>,
+    /// Run at the end of every frame, in the order they were inserted.
+    sub_apps: Vec<(&'static str, SubApp)>,
+}
+// ANCHOR_END: Scheduler
+
+// ANCHOR: SchedulerImpl
+impl Scheduler {
+    /// Runs every system once. Panics if a system's parameters aren't available, such as a
+    /// resource that was never added.
+    pub fn run(&mut self) {
+        if let Err(error) = self.try_run() {
+            panic!("{}", error);
+        }
+    }
+
+    /// Like [`Scheduler::run`], but returns an error instead of panicking if a system's
+    /// parameters aren't available. The rest of the frame is skipped, including the post-frame
+    /// hook.
+    pub fn try_run(&mut self) -> Result<(), SystemParamError> {
+        self.run_frame(None, &mut ()).map(drop)
+    }
+
+    pub fn add_system<I, S: System<In = (), Out = ()> + 'static>(
+        &mut self,
+        system: impl IntoSystem<I, System = S>,
+    ) -> SystemId {
+        self.push_system(system.into_descriptor().boxed())
+    }
+
+    /// Adds every system in the tuple, in order. The same as calling [`Scheduler::add_system`]
+    /// for each of them.
+    pub fn add_systems<I>(&mut self, systems: impl IntoSystemTuple<I>) -> Vec<SystemId> {
+        systems
+            .into_systems()
+            .into_iter()
+            .map(|system| self.push_system(system))
+            .collect()
+    }
+
+    fn push_system(&mut self, descriptor: SystemDescriptor<BoxedSystem>) -> SystemId {
+        let stored = self.store_system(descriptor);
+        let id = SystemId(self.systems.len());
+        self.systems.push(stored);
+        id
+    }
+
+    fn store_system(&mut self, descriptor: SystemDescriptor<BoxedSystem>) -> StoredSystem {
+        let SystemDescriptor {
+            system,
+            before,
+            after,
+        } = descriptor;
+        let mut accesses = AccessMap::new();
+        system.accesses(&mut accesses);
+
+        // Any system might take `Commands`, which need somewhere to queue up, and something to
+        // hand out entity ids.
+        self.resources
+            .entry(TypeId::of::<CommandQueue>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(CommandQueue::default())));
+        self.resources
+            .entry(TypeId::of::<Entities>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(Entities::default())));
+
+        StoredSystem {
+            system,
+            accesses,
+            before,
+            after,
+        }
+    }
+
+    /// Adds `res` to the scheduler. If there already was a resource of type `R`, it gets replaced
+    /// and handed back, like [`HashMap::insert`]. A resource added with
+    /// [`Scheduler::add_shared_resource`] is only handed back if nothing else has a handle to it.
+    pub fn add_resource<R: 'static>(&mut self, res: R) -> Option<R> {
+        let old = self.insert_resource_cell(
+            TypeId::of::<R>(),
+            std::any::type_name::<R>(),
+            Box::new(res),
+        )?;
+
+        unbox_resource(old)
+    }
+
+    pub fn contains_resource<T: 'static>(&self) -> bool {
+        Moved::cell::<T>(&self.resources).is_some()
+    }
+
+    fn insert_resource_cell(
+        &mut self,
+        id: TypeId,
+        name: &'static str,
+        value: Box<dyn Any>,
+    ) -> Option<Box<dyn Any>> {
+        let old = self.resources.insert(id, UnsafeCell::new(value));
+        self.resource_names.insert(id, name);
+
+        self.resources
+            .entry(TypeId::of::<AccessCounts>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(AccessCounts::default())));
+        ChangeTicks::mark_inserted(&mut self.resources, id);
+
+        old.map(UnsafeCell::into_inner)
+    }
+}
+// ANCHOR_END: SchedulerImpl
+
+// ANCHOR: Capacity
+impl Scheduler {
+    /// Creates a scheduler with room for at least `systems` systems and `resources` resources
+    /// before it needs to reallocate.
+    pub fn with_capacity(systems: usize, resources: usize) -> Self {
+        Scheduler {
+            systems: Vec::with_capacity(systems),
+            // Extra slots for the `AccessCounts` and `ChangeTicks` that are added alongside the first
+            // resource, and the `CommandQueue` and `Entities` that are added alongside the first
+            // system.
+            resources: TypeMap::with_capacity(resources + 4),
+            resource_names: HashMap::with_capacity(resources),
+            ..Default::default()
+        }
+    }
+
+    /// Makes room for at least `additional` more resources.
+    pub fn reserve_resources(&mut self, additional: usize) {
+        // Leave room for `AccessCounts`, `ChangeTicks`, `CommandQueue` and `Entities` too, in case
+        // they haven't been added yet.
+        self.resources.reserve(additional + 4);
+        self.resource_names.reserve(additional);
+    }
+
+    /// How many resources can be stored before the resource map has to grow.
+    pub fn resource_capacity(&self) -> usize {
+        self.resources.capacity()
+    }
+}
+// ANCHOR_END: Capacity
+
+// ANCHOR: FrameHooks
+type FrameHook = Box<dyn FnMut(&mut Scheduler)>;
+
+impl Scheduler {
+    /// Sets a hook that runs with exclusive access to the scheduler at the start of every frame,
+    /// before any systems.
+    pub fn set_pre_frame(&mut self, hook: FrameHook) {
+        self.pre_frame = Some(hook);
+    }
+
+    /// Sets a hook that runs with exclusive access to the scheduler at the end of every frame,
+    /// after all systems.
+    pub fn set_post_frame(&mut self, hook: FrameHook) {
+        self.post_frame = Some(hook);
+    }
+
+    fn call_frame_hook(&mut self, slot: fn(&mut Scheduler) -> &mut Option<FrameHook>) {
+        // The hook can't be inside the scheduler while it's being handed `&mut` the scheduler, so
+        // take it out for the duration of the call.
+        if let Some(mut hook) = slot(self).take() {
+            hook(self);
+
+            // Don't clobber a replacement hook that was set from inside the hook itself.
+            let slot = slot(self);
+            if slot.is_none() {
+                *slot = Some(hook);
+            }
+        }
+    }
+}
+// ANCHOR_END: FrameHooks
+
+// ANCHOR: SharedLocalScheduler
+/// A scheduler shared between several owners on a single thread, such as the callbacks of a wasm
+/// app. Cloning it clones the handle, not the scheduler.
+#[derive(Clone, Default)]
+struct SharedLocalScheduler(Rc<RefCell<Scheduler>>);
+
+/// The shared scheduler was already in use further up the call stack.
+#[derive(Debug, PartialEq, Eq)]
+struct AlreadyBorrowed;
+
+impl SharedLocalScheduler {
+    pub fn new(scheduler: Scheduler) -> Self {
+        SharedLocalScheduler(Rc::new(RefCell::new(scheduler)))
+    }
+
+    /// Gives `f` exclusive access to the scheduler, or fails if something else is already using it
+    /// (for example, if a system triggered a callback that tries to use the scheduler again).
+    pub fn with<R>(&self, f: impl FnOnce(&mut Scheduler) -> R) -> Result<R, AlreadyBorrowed> {
+        let mut scheduler = self.0.try_borrow_mut().map_err(|_| AlreadyBorrowed)?;
+        Ok(f(&mut scheduler))
+    }
+
+    pub fn run(&self) -> Result<(), AlreadyBorrowed> {
+        self.with(Scheduler::run)
+    }
+}
+
+impl From<Scheduler> for SharedLocalScheduler {
+    fn from(scheduler: Scheduler) -> Self {
+        SharedLocalScheduler::new(scheduler)
+    }
+}
+// ANCHOR_END: SharedLocalScheduler
+
+// ANCHOR: Deadline
+#[derive(Debug, Default)]
+struct RunOutcome {
+    /// The systems that hadn't started by the deadline, in the order they would have run. They
+    /// run as early as their ordering constraints allow on the next frame.
+    deferred: Vec<SystemId>,
+}
+
+/// What happened to a system during a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SystemStatus {
+    Ran,
+    /// The system decided not to run, because of a run condition, [`Paused`] or a disabled
+    /// feature.
+    Skipped,
+}
+
+/// Gets told about every system a frame runs, in the order they run. This is how the reports
+/// built on top of the scheduler (benchmarks, telemetry and so on) see a frame without having to
+/// run it themselves.
+trait FrameObserver {
+    /// Called right before system `id` runs.
+    fn started(&mut self, _id: SystemId) {}
+
+    /// Called right after system `id` has run (or skipped itself) and its commands have been
+    /// applied.
+    fn finished(&mut self, _id: SystemId, _status: SystemStatus) {}
+}
+
+/// Observes nothing, for when all we want is to run the frame.
+impl FrameObserver for () {}
+
+impl Scheduler {
+    /// Runs the schedule, but stops starting new systems once `deadline` has passed. Systems that
+    /// are already running are never interrupted, so a frame can still overshoot the deadline by
+    /// up to one system.
+    pub fn run_with_deadline(&mut self, deadline: Instant) -> RunOutcome {
+        self.run_frame(Some(deadline), &mut ())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Runs one frame. Every way of running the schedule goes through here, so they all agree on
+    /// what a frame is: startup systems, time, events, hooks and sub-apps included.
+    fn run_frame(
+        &mut self,
+        deadline: Option<Instant>,
+        observer: &mut dyn FrameObserver,
+    ) -> Result<RunOutcome, SystemParamError> {
+        self.run_startup()?;
+        self.advance_real_time();
+        self.update_events();
+        self.call_frame_hook(|scheduler| &mut scheduler.pre_frame);
+        let outcome = self.run_systems(deadline, observer)?;
+        self.call_frame_hook(|scheduler| &mut scheduler.post_frame);
+        self.run_sub_apps()?;
+
+        Ok(outcome)
+    }
+
+    fn run_systems(
+        &mut self,
+        deadline: Option<Instant>,
+        observer: &mut dyn FrameObserver,
+    ) -> Result<RunOutcome, SystemParamError> {
+        // Nothing to run means nothing to defer either, so skip the bookkeeping entirely.
+        if self.systems.is_empty() {
+            return Ok(RunOutcome::default());
+        }
+
+        // Whatever didn't get a turn last frame is moved up, as far as its constraints allow.
+        // Every system still runs once per frame, not twice.
+        let schedule = self.schedule_order();
+
+        let mut deferred = Vec::new();
+        for id in schedule {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                deferred.push(id);
+                continue;
+            }
+
+            observer.started(id);
+            let status = Scheduler::run_system(
+                &mut self.systems[id.0].system,
+                &mut self.resources,
+                &mut self.resource_names,
+                self.watchdog.as_ref(),
+            )?;
+            observer.finished(id, status);
+        }
+
+        self.deferred.clone_from(&deferred);
+        Ok(RunOutcome { deferred })
+    }
+
+    /// Runs one system and cleans up after it. Takes the fields it needs rather than `&mut self`,
+    /// since the system itself is borrowed from one of them.
+    fn run_system(
+        system: &mut BoxedSystem,
+        resources: &mut TypeMap,
+        resource_names: &mut HashMap<TypeId, &'static str>,
+        watchdog: Option<&Watchdog>,
+    ) -> Result<SystemStatus, SystemParamError> {
+        let watching = watchdog.map(|watchdog| watchdog.watch(system.name()));
+        let discard = DiscardOnPanic::new(resources);
+        let result = system.try_run_or_skip((), resources);
+        drop(discard);
+        drop(watching);
+        let status = match result? {
+            Some(()) => SystemStatus::Ran,
+            None => SystemStatus::Skipped,
+        };
+
+        let leaked = LiveBorrows::get(resources)
+            .map(LiveBorrows::names)
+            .unwrap_or_default();
+        assert!(
+            leaked.is_empty(),
+            "system {} leaked borrows of {leaked:?} past the end of its run",
+            system.name(),
+        );
+
+        Scheduler::apply_commands(resources, resource_names);
+        Ok(status)
+    }
+}
+// ANCHOR_END: Deadline
+
+// ANCHOR: ResourcePtr
+impl Scheduler {
+    /// Returns a raw pointer to the resource of type `T`, for handing over to code that can't hold
+    /// a borrow of the scheduler, such as C code across an FFI boundary.
+    ///
+    /// Resources live in their own heap allocation, so the pointer keeps pointing at the right
+    /// place when other resources are added and the map reallocates. Everything else is on you:
+    /// - The pointer dangles once the resource is replaced (by another `add_resource::<T>()`) or
+    ///   the scheduler is dropped.
+    /// - The pointer does not participate in access tracking. It must not be used while anything
+    ///   else may be accessing `T`, which in practice means not while the schedule is running.
+    /// - Any later access to `T` through the scheduler may invalidate the pointer as far as the
+    ///   aliasing rules are concerned. The safest habit is to fetch a fresh pointer each time it is
+    ///   handed out rather than caching one across calls into the scheduler.
+    pub fn resource_ptr<T: 'static>(&self) -> Option<*const T> {
+        let value = self.resources.get(&TypeId::of::<T>())?.get();
+
+        // SAFETY:
+        // We hold `&self`, so no system is running and nobody can be mutating the resource.
+        let value = unsafe { &*value };
+
+        value.downcast_ref::<T>().map(|value| value as *const T)
+    }
+
+    /// Like [`Scheduler::resource_ptr`], but the pointer may also be written through. This takes
+    /// `&mut self` because producing it requires briefly borrowing the resource uniquely.
+    ///
+    /// The same contract applies: the pointer is only usable until the resource is replaced or
+    /// the scheduler is dropped, and only while nothing else is accessing `T`.
+    pub fn resource_ptr_mut<T: 'static>(&mut self) -> Option<*mut T> {
+        let value = self.resources.get_mut(&TypeId::of::<T>())?.get_mut();
+
+        value.downcast_mut::<T>().map(|value| value as *mut T)
+    }
+}
+// ANCHOR_END: ResourcePtr
+
+// ANCHOR: GetResource
+impl Scheduler {
+    /// Returns the resource of type `T`, or `None` if there isn't one.
+    pub fn get_resource<T: 'static>(&self) -> Option<&T> {
+        let value = self.resources.get(&TypeId::of::<T>())?.get();
+
+        // SAFETY:
+        // Systems only run inside methods that take `&mut self`, so while we hold `&self` nothing
+        // can be mutating the resource, and the returned borrow keeps it that way.
+        let value = unsafe { &*value };
+
+        match value.downcast_ref::<Arc<T>>() {
+            Some(shared) => Some(shared),
+            None => value.downcast_ref::<T>(),
+        }
+    }
+
+    /// Returns the resource of type `T` for modifying, or `None` if there isn't one.
+    pub fn get_resource_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        let id = TypeId::of::<T>();
+        // No unsafe needed here: `&mut self` means nothing else can be touching any resource.
+        // Checked first, since only handing out a `&mut T` counts as a change. A shared resource
+        // or a `ResOwned` placeholder doesn't.
+        if !self.resources.get_mut(&id)?.get_mut().is::<T>() {
+            return None;
+        }
+
+        // There's no telling whether the caller changes it, so assume they do.
+        if let Some(ticks) = ChangeTicks::get(&self.resources) {
+            ticks.mark(id);
+        }
+
+        self.resources.get_mut(&id)?.get_mut().downcast_mut::<T>()
+    }
+
+    /// Takes the resource of type `T` out of the scheduler and hands it back, or returns `None`
+    /// if there isn't one. Useful for closing files and sockets at a known point, rather than
+    /// whenever the scheduler happens to be dropped.
+    ///
+    /// A resource added with [`Scheduler::add_shared_resource`] is removed either way, but only
+    /// handed back if nothing else has a handle to it.
+    pub fn remove_resource<T: 'static>(&mut self) -> Option<T> {
+        let id = TypeId::of::<T>();
+        let value = self.resources.remove(&id)?.into_inner();
+        self.resource_names.remove(&id);
+
+        unbox_resource(value)
+    }
+}
+// ANCHOR_END: GetResource
+
+// ANCHOR: InitResource
+impl Scheduler {
+    /// Adds `T::default()` as a resource, unless there already is a resource of type `T`.
+    pub fn init_resource<T: Default + 'static>(&mut self) {
+        if !self.contains_resource::<T>() {
+            self.add_resource(T::default());
+        }
+    }
+
+    /// Returns the resource of type `T` for modifying, adding the one `default` makes first if
+    /// there isn't one yet. Panics if `T` was added with `add_shared_resource`, since that can't
+    /// be changed.
+    pub fn get_or_insert_resource<T: 'static>(&mut self, default: impl FnOnce() -> T) -> &mut T {
+        if !self.contains_resource::<T>() {
+            self.add_resource(default());
+        }
+
+        self.get_resource_mut::<T>().unwrap_or_else(|| {
+            panic!(
+                "resource {} was added with `add_shared_resource`, so it can't be changed",
+                std::any::type_name::<T>(),
+            )
+        })
+    }
+}
+// ANCHOR_END: InitResource
+
+// ANCHOR: ResourceScope
+impl Scheduler {
+    /// Takes the resource of type `T` out for the duration of `f`, so that `f` can change it and
+    /// the rest of the scheduler at the same time, then puts it back.
+    ///
+    /// Taking it out and putting it back doesn't count as a change for [`Changed`]. If `f` changes
+    /// the resource in a way systems should notice, it can go through
+    /// [`Scheduler::get_resource_mut`] afterwards.
+    ///
+    /// A resource added with [`Scheduler::add_shared_resource`] can only be changed while nothing
+    /// else has a handle to it. Panics if there is no resource of type `T`, if it is shared with
+    /// someone else, or if `f` adds one. If `f` panics, the resource is lost.
+    pub fn resource_scope<T: 'static, R>(
+        &mut self,
+        f: impl FnOnce(&mut Scheduler, &mut T) -> R,
+    ) -> R {
+        let id = TypeId::of::<T>();
+        let type_name = std::any::type_name::<T>();
+
+        // Checked before taking anything out, so that the resource isn't lost along with the
+        // panic. A placeholder counts as missing, and is left for the next run to clean up.
+        assert!(
+            self.contains_resource::<T>(),
+            "resource_scope needs a resource of type {}, but there isn't one",
+            type_name,
+        );
+        let shared = self
+            .resources
+            .get_mut(&id)
+            .and_then(|cell| cell.get_mut().downcast_mut::<Arc<T>>());
+        assert!(
+            shared.is_none_or(|shared| Arc::get_mut(shared).is_some()),
+            "resource {} is shared with someone else, so it can't be changed",
+            type_name,
+        );
+
+        let mut cell = self.resources.remove(&id).unwrap();
+        let name = self.resource_names.remove(&id);
+
+        // Borrowing `cell` rather than unboxing it means it goes back exactly as it was, shared
+        // or not.
+        let value = cell.get_mut();
+        let value = if value.is::<Arc<T>>() {
+            Arc::get_mut(value.downcast_mut::<Arc<T>>().unwrap()).unwrap()
+        } else {
+            value.downcast_mut::<T>().unwrap()
+        };
+
+        let result = f(self, value);
+
+        assert!(
+            !self.contains_resource::<T>(),
+            "resource {} was added during its own resource_scope",
+            type_name,
+        );
+        self.resources.insert(id, cell);
+        if let Some(name) = name {
+            self.resource_names.insert(id, name);
+        }
+        result
+    }
+}
+// ANCHOR_END: ResourceScope
+
+// ANCHOR: DebugDump
+impl Scheduler {
+    /// Renders everything the scheduler knows about into a human-readable report. Handy for
+    /// pasting into bug reports.
+    ///
+    /// Systems are listed in the order they'll run in on the next frame, starting with any
+    /// startup systems that haven't run yet. Panics if the ordering constraints form a cycle,
+    /// like [`Scheduler::schedule_order`].
+    pub fn debug_dump(&self) -> String {
+        use std::fmt::Write;
+
+        let mut dump = String::new();
+
+        let mut resources: Vec<_> = self.resource_names.values().collect();
+        resources.sort();
+        writeln!(dump, "resources ({}):", resources.len()).unwrap();
+        for name in resources {
+            writeln!(dump, "    {name}").unwrap();
+        }
+
+        let startup = order_systems(&self.startup_systems, &[], false)
+            .into_iter()
+            .map(|id| ("startup".to_owned(), &self.startup_systems[id.0]));
+        let order = self
+            .schedule_order()
+            .into_iter()
+            .map(|id| (id.0.to_string(), &self.systems[id.0]));
+        let systems: Vec<_> = startup.chain(order).collect();
+
+        writeln!(dump, "systems ({}):", systems.len()).unwrap();
+        for (label, stored) in systems {
+            writeln!(dump, "    {label}: {}", stored.system.name()).unwrap();
+
+            for name in &stored.after {
+                writeln!(dump, "        after {name}").unwrap();
+            }
+            for name in &stored.before {
+                writeln!(dump, "        before {name}").unwrap();
+            }
+
+            let mut conditions = Vec::new();
+            stored
+                .system
+                .describe_conditions(&self.resources, &mut conditions);
+            for condition in conditions {
+                writeln!(dump, "        {condition}").unwrap();
+            }
+
+            let mut accesses: Vec<_> = stored
+                .accesses
+                .iter()
+                .map(|(key, access)| (self.access_name(key), access))
+                .collect();
+            accesses.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, access) in accesses {
+                writeln!(dump, "        {access:?} {name}").unwrap();
+            }
+        }
+
+        dump
+    }
+
+    fn access_name(&self, key: &AccessKey) -> String {
+        match key {
+            AccessKey::Resource(id) => match self.resource_names.get(id) {
+                Some(name) => name.to_string(),
+                None => format!("<unregistered {id:?}>"),
+            },
+            AccessKey::Component(_, name) => format!("component {name}"),
+        }
+    }
+}
+// ANCHOR_END: DebugDump
+
+// ANCHOR: Benchmark
+impl Scheduler {
+    /// Runs `iterations` frames, like calling [`Scheduler::run`] that many times, timing every
+    /// system in every frame. A system's time includes applying its commands.
+    pub fn benchmark_schedule(&mut self, iterations: usize) -> ScheduleBenchmark {
+        assert!(
+            iterations > 0,
+            "cannot benchmark a schedule over zero iterations"
+        );
+
+        let mut recorder = SystemRecorder::new(self.systems.len(), iterations);
+        for _ in 0..iterations {
+            if let Err(error) = self.run_frame(None, &mut recorder) {
+                panic!("{}", error);
+            }
+        }
+
+        let systems = recorder
+            .samples
+            .into_iter()
+            .enumerate()
+            // A system added partway through never ran in the frames before it.
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(index, samples)| {
+                let samples = samples.into_iter().map(|(elapsed, _)| elapsed).collect();
+                SystemTimings::from_samples(
+                    SystemId(index),
+                    self.systems[index].system.name(),
+                    samples,
+                )
+            })
+            .collect();
+
+        ScheduleBenchmark {
+            iterations,
+            systems,
+        }
+    }
+}
+
+/// Records how long every system took and whether it ran, over one or more frames.
+struct SystemRecorder {
+    start: Instant,
+    /// Every sample of every system, indexed by [`SystemId`]. It grows as needed, since a frame
+    /// hook can add systems while the frames are already underway.
+    samples: Vec<Vec<(Duration, SystemStatus)>>,
+}
+
+impl SystemRecorder {
+    /// Makes room for `frames` samples of each of the first `systems` systems up-front, so that
+    /// recording them never allocates.
+    fn new(systems: usize, frames: usize) -> Self {
+        SystemRecorder {
+            start: Instant::now(),
+            samples: (0..systems).map(|_| Vec::with_capacity(frames)).collect(),
+        }
+    }
+}
+
+impl FrameObserver for SystemRecorder {
+    fn started(&mut self, _id: SystemId) {
+        self.start = Instant::now();
+    }
+
+    fn finished(&mut self, id: SystemId, status: SystemStatus) {
+        let elapsed = self.start.elapsed();
+        if self.samples.len() <= id.0 {
+            self.samples.resize_with(id.0 + 1, Vec::new);
+        }
+        self.samples[id.0].push((elapsed, status));
+    }
+}
+
+#[derive(Debug)]
+struct ScheduleBenchmark {
+    iterations: usize,
+    /// One entry per system that ran, in the order they were added to the scheduler.
+    systems: Vec<SystemTimings>,
+}
+
+#[derive(Debug)]
+struct SystemTimings {
+    id: SystemId,
+    name: Cow<'static, str>,
+    min: Duration,
+    max: Duration,
+    mean: Duration,
+    median: Duration,
+    p95: Duration,
+}
+
+impl SystemTimings {
+    fn from_samples(id: SystemId, name: Cow<'static, str>, mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+
+        let len = samples.len();
+        let total: Duration = samples.iter().sum();
+        let median = if len.is_multiple_of(2) {
+            (samples[len / 2 - 1] + samples[len / 2]) / 2
+        } else {
+            samples[len / 2]
+        };
+        // Nearest-rank percentile: the smallest sample that is >= 95% of all samples.
+        let p95 = samples[(len * 95).div_ceil(100) - 1];
+
+        SystemTimings {
+            id,
+            name,
+            min: samples[0],
+            max: samples[len - 1],
+            mean: total / len as u32,
+            median,
+            p95,
+        }
+    }
+}
+// ANCHOR_END: Benchmark
+
+// ANCHOR: Telemetry
+impl Scheduler {
+    /// Runs a frame, like [`Scheduler::run`], and reports what happened as JSON, for feeding
+    /// into external dashboards. Each system in the frame gets an entry with its id, name,
+    /// whether it ran or skipped itself, how long it took in nanoseconds (including applying its
+    /// commands), the resources it accessed and the ids of the systems it conflicts with.
+    pub fn run_and_export_json(&mut self) -> String {
+        use std::fmt::Write;
+
+        let mut recorder = SystemRecorder::new(self.systems.len(), 1);
+        if let Err(error) = self.run_frame(None, &mut recorder) {
+            panic!("{}", error);
+        }
+
+        // Systems added after their turn would have come, like by the post-frame hook, weren't
+        // part of the frame.
+        let entries = recorder
+            .samples
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, samples)| Some((index, *samples.first()?)));
+
+        let mut json = String::from("{\"systems\":[");
+        for (index, (elapsed, status)) in entries {
+            if !json.ends_with('[') {
+                json.push(',');
+            }
+            let stored = &self.systems[index];
+
+            let mut accesses: Vec<_> = stored
+                .accesses
+                .iter()
+                .map(|(key, access)| (self.access_name(key), access))
+                .collect();
+            accesses.sort_by(|a, b| a.0.cmp(&b.0));
+
+            // Systems that touch the same resource, at least one of them writing, can never run
+            // at the same time.
+            let conflicting: Vec<_> = self
+                .systems
+                .iter()
+                .enumerate()
+                .filter(|(other, other_stored)| {
+                    *other != index && conflicts(&stored.accesses, &other_stored.accesses)
+                })
+                .map(|(other, _)| other.to_string())
+                .collect();
+
+            write!(
+                json,
+                "{{\"id\":{index},\"name\":{},\"status\":\"{status:?}\",\"nanos\":{},\"accesses\":{{",
+                json_string(&stored.system.name()),
+                elapsed.as_nanos(),
+            )
+            .unwrap();
+            for (i, (name, access)) in accesses.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write!(json, "{}:\"{access:?}\"", json_string(name)).unwrap();
+            }
+            write!(json, "}},\"conflicts\":[{}]}}", conflicting.join(",")).unwrap();
+        }
+        json.push_str("]}");
+
+        json
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+// ANCHOR_END: Telemetry
+
+// ANCHOR: CountingAllocator
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// A global allocator that forwards to the system allocator while counting every allocation.
+///
+/// The binary has to install it with `#[global_allocator]` for
+/// [`Scheduler::run_and_measure_allocations`] to see anything; without it every count is zero.
+/// The count is process-wide, so allocations made by other threads during a system's run are
+/// attributed to that system too.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: The caller upholds `GlobalAlloc::alloc`'s contract, which we forward as-is.
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `ptr` was allocated by `std::alloc::System`, via the methods above.
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: The caller upholds `GlobalAlloc::realloc`'s contract, which we forward as-is.
+        unsafe { std::alloc::System.realloc(ptr, layout, new_size) }
+    }
+}
+
+impl Scheduler {
+    /// Runs a frame, like [`Scheduler::run`], and reports how many allocations (including
+    /// reallocations) each system made, in the order they ran. Allocations made while applying a
+    /// system's commands count towards that system. Requires [`CountingAllocator`] to be the
+    /// global allocator.
+    pub fn run_and_measure_allocations(&mut self) -> Vec<(SystemId, usize)> {
+        // Allocated up-front, so that recording a count never shows up in the next one.
+        let mut counter = AllocationCounter {
+            before: 0,
+            counts: Vec::with_capacity(self.systems.len()),
+        };
+        if let Err(error) = self.run_frame(None, &mut counter) {
+            panic!("{}", error);
+        }
+
+        counter.counts
+    }
+}
+
+struct AllocationCounter {
+    before: usize,
+    counts: Vec<(SystemId, usize)>,
+}
+
+impl FrameObserver for AllocationCounter {
+    fn started(&mut self, _id: SystemId) {
+        self.before = ALLOCATIONS.load(Ordering::Relaxed);
+    }
+
+    fn finished(&mut self, id: SystemId, _status: SystemStatus) {
+        let after = ALLOCATIONS.load(Ordering::Relaxed);
+        self.counts.push((id, after - self.before));
+    }
+}
+// ANCHOR_END: CountingAllocator
+// ANCHOR: Extract
+impl Scheduler {
+    /// Runs this schedule, then hands both schedulers to `extract` so it can copy whatever the
+    /// `render` schedule needs out of this one, then runs `render`.
+    ///
+    /// `extract` only gets shared access to this scheduler: the extract step reads the main
+    /// schedule's results, it doesn't change them.
+    pub fn run_with_extract(
+        &mut self,
+        render: &mut Scheduler,
+        mut extract: impl FnMut(&Scheduler, &mut Scheduler),
+    ) {
+        self.run();
+        extract(self, render);
+        render.run();
+    }
+}
+// ANCHOR_END: Extract
+
+// ANCHOR: SubApp
+/// A scheduler that runs inside another one, with its own systems and resources. Every frame,
+/// once the parent is done, `extract` copies what the sub-app needs out of the parent, and then
+/// the sub-app runs.
+struct SubApp {
+    scheduler: Scheduler,
+    extract: ExtractFn,
+}
+
+type ExtractFn = Box<dyn FnMut(&Scheduler, &mut Scheduler)>;
+
+impl SubApp {
+    pub fn new(
+        scheduler: Scheduler,
+        extract: impl FnMut(&Scheduler, &mut Scheduler) + 'static,
+    ) -> Self {
+        SubApp {
+            scheduler,
+            extract: Box::new(extract),
+        }
+    }
+}
+
+impl Scheduler {
+    /// Adds a sub-app to run at the end of every frame, after the post-frame hook. If there
+    /// already was one with the same label, it gets replaced and handed back.
+    pub fn insert_sub_app(&mut self, label: &'static str, sub_app: SubApp) -> Option<SubApp> {
+        match self
+            .sub_apps
+            .iter_mut()
+            .find(|(existing, _)| *existing == label)
+        {
+            Some((_, existing)) => Some(std::mem::replace(existing, sub_app)),
+            None => {
+                self.sub_apps.push((label, sub_app));
+                None
+            }
+        }
+    }
+
+    /// The scheduler of the sub-app with this label, e.g. for adding systems and resources to it.
+    pub fn sub_app_mut(&mut self, label: &str) -> Option<&mut Scheduler> {
+        self.sub_apps
+            .iter_mut()
+            .find(|(existing, _)| *existing == label)
+            .map(|(_, sub_app)| &mut sub_app.scheduler)
+    }
+
+    fn run_sub_apps(&mut self) -> Result<(), SystemParamError> {
+        // Taken out so that `extract` can see the rest of the parent while changing the sub-app.
+        // Sub-apps can't see each other during the extract step as a result.
+        let sub_apps = std::mem::take(&mut self.sub_apps);
+        let mut guard = RestoreSubApps {
+            parent: self,
+            sub_apps,
+        };
+
+        let RestoreSubApps { parent, sub_apps } = &mut guard;
+        sub_apps.iter_mut().try_for_each(|(_, sub_app)| {
+            (sub_app.extract)(parent, &mut sub_app.scheduler);
+            sub_app.scheduler.try_run()
+        })
+    }
+}
+
+/// Puts the sub-apps [`Scheduler::run_sub_apps`] took out back into their parent when dropped, so
+/// that they aren't lost if an `extract` function or one of their systems panics.
+struct RestoreSubApps<'a> {
+    parent: &'a mut Scheduler,
+    sub_apps: Vec<(&'static str, SubApp)>,
+}
+
+impl Drop for RestoreSubApps<'_> {
+    fn drop(&mut self) {
+        self.parent.sub_apps = std::mem::take(&mut self.sub_apps);
+    }
+}
+// ANCHOR_END: SubApp
+// ANCHOR: SystemRegistry
+/// Maps names to functions that build a fresh system, so schedules can be described by name in
+/// data files (or by mods) instead of in code.
+#[derive(Default)]
+struct SystemRegistry {
+    factories: HashMap<String, Box<dyn Fn() -> BoxedSystem>>,
+}
+
+impl SystemRegistry {
+    /// Registers `system` under `name`. Every schedule that mentions `name` gets its own copy of
+    /// the system, which is why it has to be `Clone`. Plain functions always are.
+    pub fn register<I, S: System<In = (), Out = ()> + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        system: impl IntoSystem<I, System = S> + Clone + 'static,
+    ) {
+        self.factories.insert(
+            name.into(),
+            Box::new(move || Box::new(system.clone().into_system())),
+        );
+    }
+}
+
+/// A schedule description named a system that isn't in the registry.
+#[derive(Debug, PartialEq, Eq)]
+struct UnknownSystem {
+    /// The 1-based line the name was found on.
+    line: usize,
+    name: String,
+}
+
+impl Scheduler {
+    /// Adds the systems named in `description` to the schedule, in order, building each one from
+    /// `registry`. The description has one system name per line; blank lines and lines starting
+    /// with `#` are skipped.
+    ///
+    /// Every name is checked before anything is added, so on error the schedule is left as it was.
+    pub fn load_from(
+        &mut self,
+        description: &str,
+        registry: &SystemRegistry,
+    ) -> Result<Vec<SystemId>, UnknownSystem> {
+        let mut factories = Vec::new();
+        for (index, line) in description.lines().enumerate() {
+            let name = line.trim();
+            if name.is_empty() || name.starts_with('#') {
+                continue;
+            }
+
+            match registry.factories.get(name) {
+                Some(factory) => factories.push(factory),
+                None => {
+                    return Err(UnknownSystem {
+                        line: index + 1,
+                        name: name.to_string(),
+                    })
+                }
+            }
+        }
+
+        Ok(factories
+            .into_iter()
+            .map(|factory| self.push_system(SystemDescriptor::new(factory())))
+            .collect())
+    }
+}
+// ANCHOR_END: SystemRegistry
+// ANCHOR: MigrateResource
+impl Scheduler {
+    /// Replaces the `Old` resource with the `New` one that `migrate` builds out of it, for
+    /// upgrading data from older save files. Returns `false`, without calling `migrate`, if there
+    /// is no `Old` resource.
+    pub fn migrate_resource<Old: 'static, New: 'static>(
+        &mut self,
+        migrate: impl FnOnce(Old) -> New,
+    ) -> bool {
+        let Some(old) = self.remove_resource::<Old>() else {
+            return false;
+        };
+
+        self.add_resource(migrate(old));
+        true
+    }
+}
+// ANCHOR_END: MigrateResource
+// ANCHOR: Watchdog
+impl Scheduler {
+    /// Starts a background thread that calls `on_hang` with a system's name when that system has
+    /// been running for longer than `timeout`. It's called at most once per run of a system, and
+    /// may be called up to a quarter of `timeout` late, or a millisecond for shorter timeouts.
+    ///
+    /// `on_hang` runs on the watchdog thread, so it can't interrupt the stuck system. Logging the
+    /// name or calling `std::process::abort` are about all it can usefully do.
+    ///
+    /// Every system the scheduler runs is watched, startup systems included, however the frame
+    /// is run. Sub-apps need a watchdog of their own. Replacing or dropping the watchdog stops
+    /// its thread.
+    pub fn set_watchdog(&mut self, timeout: Duration, on_hang: impl Fn(&str) + Send + 'static) {
+        self.watchdog = Some(Watchdog::spawn(timeout, on_hang));
+    }
+}
+
+struct Watchdog {
+    shared: Arc<WatchdogShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct WatchdogShared {
+    /// The system the scheduler is running right now, if any.
+    running: Mutex<Option<RunningSystem>>,
+    stop: AtomicBool,
+}
+
+struct RunningSystem {
+    name: Cow<'static, str>,
+    started: Instant,
+    reported: bool,
+}
+
+impl Watchdog {
+    fn spawn(timeout: Duration, on_hang: impl Fn(&str) + Send + 'static) -> Self {
+        let shared = Arc::new(WatchdogShared::default());
+        // Waking up more often than this would just be burning a core, and a zero timeout would
+        // never sleep at all.
+        let poll = (timeout / 4).max(Duration::from_millis(1));
+
+        let thread = std::thread::spawn({
+            let shared = shared.clone();
+            move || {
+                while !shared.stop.load(Ordering::Relaxed) {
+                    std::thread::park_timeout(poll);
+
+                    let hung = {
+                        let mut running = shared.running.lock().unwrap();
+                        match running.as_mut() {
+                            Some(running)
+                                if !running.reported && running.started.elapsed() >= timeout =>
+                            {
+                                running.reported = true;
+                                Some(running.name.clone())
+                            }
+                            _ => None,
+                        }
+                    };
+
+                    // Don't hold the lock while calling out, or the scheduler would have to wait
+                    // for `on_hang` before it could move on to the next system.
+                    if let Some(name) = hung {
+                        on_hang(&name);
+                    }
+                }
+            }
+        });
+
+        Watchdog {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Marks `name` as running until the returned guard is dropped. Using a guard means a system
+    /// that panics doesn't get reported as hung afterwards.
+    fn watch(&self, name: Cow<'static, str>) -> Watching<'_> {
+        *self.shared.running.lock().unwrap() = Some(RunningSystem {
+            name,
+            started: Instant::now(),
+            reported: false,
+        });
+
+        Watching(self)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            // The thread only panics if `on_hang` does, and there's nothing useful to do about
+            // that here.
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Watching<'a>(&'a Watchdog);
+
+impl Drop for Watching<'_> {
+    fn drop(&mut self) {
+        *self.0.shared.running.lock().unwrap() = None;
+    }
+}
+// ANCHOR_END: Watchdog
+// ANCHOR: AutoOrder
+impl Scheduler {
+    /// When enabled, [`Scheduler::run`] reorders systems so that every system writing a resource
+    /// runs before the systems that only read it. Off by default, so systems run in the order
+    /// they were added.
+    ///
+    /// The rule, applied every frame:
+    /// - A system that writes `T` runs before any system that reads `T`.
+    /// - Among systems that are free to run next, the one added first goes first.
+    /// - If the rule contradicts itself (two systems that each write what the other reads), the
+    ///   earliest-added system that hasn't run yet goes next, and the rest of the rule carries on.
+    pub fn auto_order_by_access(&mut self, enabled: bool) {
+        self.auto_order = enabled;
+    }
+
+    /// The order the systems will run in on the next frame. Systems deferred from the last frame
+    /// go as early as their constraints allow, in the order they were deferred.
+    ///
+    /// Systems added with `.before()` or `.after()` always keep to those constraints, whether
+    /// ordering by access is enabled or not. Panics if the constraints form a cycle.
+    pub fn schedule_order(&self) -> Vec<SystemId> {
+        order_systems(&self.systems, &self.deferred, self.auto_order)
+    }
+}
+
+/// The order `systems` should run in, as described on [`Scheduler::schedule_order`]. Startup
+/// systems are put in order the same way, just without any deferred ones.
+fn order_systems(
+    systems: &[StoredSystem],
+    deferred: &[SystemId],
+    auto_order: bool,
+) -> Vec<SystemId> {
+    let len = systems.len();
+
+    // `must_precede[a]` lists the systems that have to wait for `a` because of `.before()`
+    // and `.after()`, and `should_precede[a]` the ones that wait for it because of accesses.
+    let mut must_precede = vec![Vec::new(); len];
+    let mut should_precede = vec![Vec::new(); len];
+
+    let names: Vec<_> = systems.iter().map(|stored| stored.system.name()).collect();
+    let named = |name: &Cow<'static, str>| -> Vec<usize> {
+        (0..len).filter(|&index| names[index] == *name).collect()
+    };
+    for (index, stored) in systems.iter().enumerate() {
+        for later in stored.before.iter().flat_map(named) {
+            must_precede[index].push(later);
+        }
+        for earlier in stored.after.iter().flat_map(named) {
+            must_precede[earlier].push(index);
+        }
+    }
+
+    if auto_order {
+        for (writer, writes) in systems.iter().enumerate() {
+            for (reader, reads) in systems.iter().enumerate() {
+                let depends = writes.accesses.iter().any(|(id, access)| {
+                    *access == Access::Write && reads.accesses.get(id) == Some(&Access::Read)
+                });
+                if depends {
+                    should_precede[writer].push(reader);
+                }
+            }
+        }
+    }
+
+    if let Some(cycle) = find_cycle(&must_precede) {
+        let names: Vec<_> = cycle.iter().map(|&index| names[index].as_ref()).collect();
+        panic!(
+            "systems have ordering constraints that form a cycle: {}",
+            names.join(" -> "),
+        );
+    }
+
+    // How many systems each system is still waiting for, of either kind.
+    let mut must_wait = vec![0; len];
+    let mut should_wait = vec![0; len];
+    for index in 0..len {
+        for &later in &must_precede[index] {
+            must_wait[later] += 1;
+        }
+        for &later in &should_precede[index] {
+            should_wait[later] += 1;
+        }
+    }
+
+    let mut done = vec![false; len];
+    let mut order = Vec::with_capacity(len);
+    while order.len() < len {
+        let ready = |index: usize| !done[index] && must_wait[index] == 0 && should_wait[index] == 0;
+        let next = deferred
+            .iter()
+            .map(|id| id.0)
+            .find(|&index| ready(index))
+            .or_else(|| (0..len).find(|&index| ready(index)))
+            // Only a cycle leaves nothing ready, and the constraints don't have any, so it's
+            // one made by accesses. Break it at the earliest system the constraints allow.
+            .unwrap_or_else(|| {
+                (0..len)
+                    .find(|&index| !done[index] && must_wait[index] == 0)
+                    .unwrap()
+            });
+
+        done[next] = true;
+        order.push(SystemId(next));
+        for &later in &must_precede[next] {
+            must_wait[later] -= 1;
+        }
+        for &later in &should_precede[next] {
+            should_wait[later] -= 1;
+        }
+    }
+
+    order
+}
+
+/// Returns the systems making up a cycle in `precedes`, in order, with the first one repeated at
+/// the end. `precedes[a]` lists the systems that come after `a`.
+fn find_cycle(precedes: &[Vec<usize>]) -> Option<Vec<usize>> {
+    // Peel off systems with nothing left in front of them. Whatever remains is either in a cycle
+    // or stuck behind one.
+    let mut waiting = vec![0; precedes.len()];
+    for later in precedes.iter().flatten() {
+        waiting[*later] += 1;
+    }
+    let mut ready: Vec<_> = (0..precedes.len()).filter(|&i| waiting[i] == 0).collect();
+    while let Some(index) = ready.pop() {
+        for &later in &precedes[index] {
+            waiting[later] -= 1;
+            if waiting[later] == 0 {
+                ready.push(later);
+            }
+        }
+    }
+
+    // Everything remaining still has a remaining system in front of it, so walking backwards
+    // from any of them has to come back around eventually.
+    let start = (0..precedes.len()).find(|&index| waiting[index] > 0)?;
+    let earlier = |index: usize| {
+        (0..precedes.len())
+            .find(|&other| waiting[other] > 0 && precedes[other].contains(&index))
+            .unwrap()
+    };
+    let mut path = vec![start];
+    let mut current = earlier(start);
+    while !path.contains(&current) {
+        path.push(current);
+        current = earlier(current);
+    }
+
+    // `path` runs backwards from `start`, and `current` is where the walk first looped back.
+    let position = path.iter().position(|&index| index == current).unwrap();
+    let mut cycle: Vec<_> = path[position..].iter().rev().copied().collect();
+    cycle.push(cycle[0]);
+    Some(cycle)
+}
+// ANCHOR_END: AutoOrder
+
+// ANCHOR: Validate
+impl Scheduler {
+    /// Checks that every resource the systems need has been added, without running anything.
+    /// Returns the name of each system that's missing something, along with the type name of
+    /// what it's missing. An empty list means the first run won't fail for lack of a resource.
+    ///
+    /// Resources that systems add or remove with `Commands` while running aren't taken into
+    /// account, so this is best called once setup is done.
+    pub fn validate(&self) -> Vec<(Cow<'static, str>, &'static str)> {
+        let mut missing = Vec::new();
+        for stored in self.startup_systems.iter().chain(&self.systems) {
+            let mut required = Vec::new();
+            stored.system.requires(&mut required);
+            // Both halves of a pipe can need the same resource, but once is enough to report it.
+            required.sort();
+            required.dedup();
+
+            for (id, name) in required {
+                if !self.resources.contains_key(&id) {
+                    missing.push((stored.system.name(), name));
+                }
+            }
+        }
+        missing
+    }
+}
+// ANCHOR_END: Validate
+// ANCHOR: Time
+/// How much game time has passed. Add it as a resource and the scheduler keeps it up to date, as
+/// set by [`Scheduler::set_time_mode`].
+#[derive(Debug, Default)]
+struct Time {
+    delta: Duration,
+    elapsed: Duration,
+    /// When the scheduler last advanced this in [`TimeMode::Real`].
+    last_update: Option<Instant>,
+}
+
+impl Time {
+    /// How much time passed between the last frame and this one.
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    /// How much time has passed in total.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Moves time forward by `delta`, making it this frame's delta.
+    pub fn advance_by(&mut self, delta: Duration) {
+        self.delta = delta;
+        self.elapsed += delta;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TimeMode {
+    /// Every run advances [`Time`] by however long it's been since the last run. The first run
+    /// has a delta of zero.
+    #[default]
+    Real,
+    /// [`Time`] only moves when [`Time::advance_by`] is called, which makes for reproducible
+    /// tests and fixed timesteps.
+    Manual,
+}
+
+impl Scheduler {
+    pub fn set_time_mode(&mut self, mode: TimeMode) {
+        self.time_mode = mode;
+
+        // Time spent in manual mode shouldn't show up as one huge delta when switching back.
+        if let Some(time) = self.get_resource_mut::<Time>() {
+            time.last_update = None;
+        }
+    }
+
+    fn advance_real_time(&mut self) {
+        if self.time_mode != TimeMode::Real {
+            return;
+        }
+
+        if let Some(time) = self.get_resource_mut::<Time>() {
+            let now = Instant::now();
+            let delta = time
+                .last_update
+                .map_or(Duration::ZERO, |last| now.duration_since(last));
+
+            time.advance_by(delta);
+            time.last_update = Some(now);
+        }
+    }
+}
+// ANCHOR_END: Time
+
+// ANCHOR: Events
+/// Events of type `E`, which systems send with [`EventWriter`] and receive with [`EventReader`].
+/// Added with [`Scheduler::add_event`]. An event stays around for the frame it was sent in and
+/// the one after, so every system gets to see it, whichever order they run in.
+struct Events<E> {
+    /// Events sent last frame.
+    previous: Vec<E>,
+    /// Events sent this frame.
+    current: Vec<E>,
+    /// How many events were ever sent, which is also the id the next one gets. The first event in
+    /// `previous` has the id `sent - current.len() - previous.len()`.
+    sent: usize,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Events {
+            previous: Vec::new(),
+            current: Vec::new(),
+            sent: 0,
+        }
+    }
+}
+
+impl<E> Events<E> {
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+        self.sent += 1;
+    }
+
+    /// Drops last frame's events, and makes this frame's the previous ones. The scheduler calls
+    /// this at the start of every run.
+    fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// The events with an id of at least `from`, oldest first.
+    fn since(&self, from: usize) -> impl Iterator<Item = &E> {
+        let first = self.sent - self.current.len() - self.previous.len();
+        let skip = from.saturating_sub(first);
+        self.previous.iter().chain(&self.current).skip(skip)
+    }
+}
+
+struct EventWriter<'a, E: 'static> {
+    events: ResMut<'a, Events<E>>,
+}
+
+impl<E: 'static> EventWriter<'_, E> {
+    pub fn send(&mut self, event: E) {
+        self.events.send(event);
+    }
+}
+
+impl<'res, E: 'static> SystemParam for EventWriter<'res, E> {
+    type State = ();
+    type Item<'new> = EventWriter<'new, E>;
+
+    fn accesses(access: &mut AccessMap) {
+        ResMut::<Events<E>>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        ResMut::<Events<E>>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        // SAFETY: The caller upholds the same contract for us as for `ResMut<Events<E>>`.
+        let events = unsafe { ResMut::<Events<E>>::retrieve(resources, state) }?;
+        Ok(EventWriter { events })
+    }
+}
+
+struct EventReader<'a, E: 'static> {
+    events: Res<'a, Events<E>>,
+    /// The id of the first event this reader hasn't read yet.
+    cursor: &'a mut usize,
+}
+
+impl<E: 'static> EventReader<'_, E> {
+    /// The events sent since this system last read them, oldest first. Events that are more than
+    /// a frame old are gone by then, so a system that skips frames can miss some.
+    pub fn read(&mut self) -> impl Iterator<Item = &E> {
+        let from = std::mem::replace(self.cursor, self.events.sent);
+        self.events.since(from)
+    }
+}
+
+impl<'res, E: 'static> SystemParam for EventReader<'res, E> {
+    /// `Res`'s state, and the cursor. Each system has its own, just like a `Local`.
+    type State = ((), usize);
+    type Item<'new> = EventReader<'new, E>;
+
+    fn accesses(access: &mut AccessMap) {
+        Res::<Events<E>>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        Res::<Events<E>>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        (state, cursor): &'r mut ((), usize),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        // SAFETY: The caller upholds the same contract for us as for `Res<Events<E>>`.
+        let events = unsafe { Res::<Events<E>>::retrieve(resources, state) }?;
+        Ok(EventReader { events, cursor })
+    }
+}
+
+impl Scheduler {
+    /// Adds the [`Events<E>`] resource, and has every run start by clearing out the events that
+    /// have been around for two frames.
+    pub fn add_event<E: 'static>(&mut self) {
+        if self.contains_resource::<Events<E>>() {
+            return;
+        }
+
+        self.add_resource(Events::<E>::default());
+        self.event_updaters.push(|resources| {
+            if let Some(cell) = resources.get_mut(&TypeId::of::<Events<E>>()) {
+                cell.get_mut().downcast_mut::<Events<E>>().unwrap().update();
+            }
+        });
+    }
+
+    fn update_events(&mut self) {
+        for update in &self.event_updaters {
+            update(&mut self.resources);
+        }
+    }
+}
+// ANCHOR_END: Events
+// ANCHOR: Entities
+/// Identifies an entity: a bag of components, each of a different type.
+///
+/// Indices are reused once an entity is despawned, but each reuse bumps the generation, so a
+/// handle to a despawned entity never refers to whatever took its place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+/// Hands out entity ids.
+#[derive(Default)]
+struct Allocator {
+    /// The current generation of every index that has ever been used.
+    generations: Vec<u32>,
+    /// Indices of despawned entities, ready to be reused.
+    free: Vec<u32>,
+}
+
+impl Allocator {
+    fn allocate(&mut self) -> Entity {
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.generations.push(0);
+            (self.generations.len() - 1) as u32
+        });
+
+        Entity {
+            index,
+            generation: self.generations[index as usize],
+        }
+    }
+
+    fn free(&mut self, entity: Entity) {
+        self.generations[entity.index as usize] += 1;
+        self.free.push(entity.index);
+    }
+}
+
+/// Every entity and its components. Like `CommandQueue`, it lives in the `TypeMap` so that
+/// `retrieve` can find it.
+#[derive(Default)]
+struct Entities {
+    /// Behind a `RefCell` so that `Commands` can hand out ids while systems are running.
+    allocator: RefCell<Allocator>,
+    /// The entities that exist, in the order they were spawned. An id handed out by `Commands`
+    /// only shows up here once its spawn is applied.
+    alive: Vec<Entity>,
+    /// The components of each type, by entity. Each component is in a cell of its own, because
+    /// a query hands out references to several of them at once.
+    components: HashMap<TypeId, HashMap<Entity, UnsafeCell<Box<dyn Any>>>>,
+}
+
+impl Entities {
+    fn get(resources: &TypeMap) -> Option<&Entities> {
+        let cell = resources.get(&TypeId::of::<Entities>())?;
+
+        // SAFETY:
+        // `Entities` is private, so no system can ask for it mutably. The scheduler only changes
+        // it through `&mut self`.
+        let entities = unsafe { &*cell.get() };
+
+        entities.downcast_ref::<Entities>()
+    }
+
+    fn get_mut(resources: &mut TypeMap) -> Option<&mut Entities> {
+        resources
+            .get_mut(&TypeId::of::<Entities>())?
+            .get_mut()
+            .downcast_mut::<Entities>()
+    }
+
+    /// Hands out an id for an entity that doesn't exist yet.
+    fn reserve(&self) -> Entity {
+        self.allocator.borrow_mut().allocate()
+    }
+
+    fn spawn(&mut self) -> Entity {
+        let entity = self.allocator.get_mut().allocate();
+        self.alive.push(entity);
+        entity
+    }
+
+    fn insert<C: 'static>(&mut self, entity: Entity, component: C) -> Option<C> {
+        assert!(self.alive.contains(&entity), "{:?} does not exist", entity);
+
+        let old = self
+            .components
+            .entry(TypeId::of::<C>())
+            .or_default()
+            .insert(entity, UnsafeCell::new(Box::new(component)))?;
+        Some(*old.into_inner().downcast::<C>().unwrap())
+    }
+
+    /// Removes `entity` and all of its components. Returns `false` if it didn't exist.
+    fn despawn(&mut self, entity: Entity) -> bool {
+        let Some(position) = self.alive.iter().position(|&alive| alive == entity) else {
+            return false;
+        };
+        self.alive.remove(position);
+
+        for components in self.components.values_mut() {
+            components.remove(&entity);
+        }
+        self.allocator.get_mut().free(entity);
+        true
+    }
+
+    /// The cell holding `entity`'s component of type `C`, if it has one.
+    fn cell<C: 'static>(&self, entity: Entity) -> Option<&UnsafeCell<Box<dyn Any>>> {
+        self.components.get(&TypeId::of::<C>())?.get(&entity)
+    }
+}
+
+/// Components to spawn an entity with: a tuple of any `'static` types.
+trait Bundle: 'static {
+    fn insert(self, entities: &mut Entities, entity: Entity);
+}
+
+macro_rules! impl_bundle {
+    (
+        $($params:ident),*
+    ) => {
+        #[allow(non_snake_case)]
+        #[allow(unused)]
+        impl<$($params: 'static),*> Bundle for ($($params,)*) {
+            fn insert(self, entities: &mut Entities, entity: Entity) {
+                let ($($params,)*) = self;
+                $(
+                    entities.insert(entity, $params);
+                )*
+            }
+        }
+    }
+}
+
+call_for_each_arity!(impl_bundle; T1, T2, T3, T4, T5, T6, T7, T8);
+
+impl Scheduler {
+    /// Creates a new entity without any components.
+    pub fn spawn(&mut self) -> Entity {
+        self.entities_mut().spawn()
+    }
+
+    /// Removes `entity` and all of its components. Returns `false` if it didn't exist.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        self.entities_mut().despawn(entity)
+    }
+
+    /// Gives `entity` a component, replacing and returning the one of the same type it had
+    /// before. Panics if the entity doesn't exist.
+    pub fn insert_component<C: 'static>(&mut self, entity: Entity, component: C) -> Option<C> {
+        self.entities_mut().insert(entity, component)
+    }
+
+    /// Returns `entity`'s component of type `C`, or `None` if it doesn't have one.
+    pub fn get_component<C: 'static>(&self, entity: Entity) -> Option<&C> {
+        let cell = Entities::get(&self.resources)?.cell::<C>(entity)?;
+
+        // SAFETY:
+        // Systems only run inside methods that take `&mut self`, so while we hold `&self` nothing
+        // can be mutating the component, and the returned borrow keeps it that way.
+        let component = unsafe { &*cell.get() };
+
+        component.downcast_ref::<C>()
+    }
+
+    fn entities_mut(&mut self) -> &mut Entities {
+        self.resources
+            .entry(TypeId::of::<Entities>())
+            .or_insert_with(|| UnsafeCell::new(Box::new(Entities::default())))
+            .get_mut()
+            .downcast_mut::<Entities>()
+            .unwrap()
+    }
+}
+// ANCHOR_END: Entities
+
+// ANCHOR: Query
+/// What a [`Query`] fetches for each entity: `&C` or `&mut C` for a component type `C`, or a
+/// tuple of those.
+trait QueryData {
+    type Item<'a>;
+
+    /// Like [`SystemParam::accesses`], but recording [`AccessKey::Component`]s.
+    fn accesses(access: &mut AccessMap);
+
+    /// Whether `entity` has everything this fetches.
+    fn matches(entities: &Entities, entity: Entity) -> bool;
+
+    /// SAFETY:
+    /// - `entity` must match.
+    /// - The caller must not have active conflicting references to the components this fetches.
+    unsafe fn fetch<'a>(entities: &'a Entities, entity: Entity) -> Self::Item<'a>;
+}
+
+impl<T: 'static> QueryData for &T {
+    type Item<'a> = &'a T;
+
+    fn accesses(access: &mut AccessMap) {
+        assert_eq!(
+            *access.entry(AccessKey::component::<T>()).or_insert(Access::Read),
+            Access::Read,
+            "conflicting access in system; attempting to access {} mutably and immutably at the same
+            time",
+            std::any::type_name::<T>(),
+        );
+    }
+
+    fn matches(entities: &Entities, entity: Entity) -> bool {
+        entities.cell::<T>(entity).is_some()
+    }
+
+    unsafe fn fetch(entities: &Entities, entity: Entity) -> &T {
+        let component = entities.cell::<T>(entity).unwrap().get();
+
+        // SAFETY: The caller asserts that nothing is changing this component.
+        let component = unsafe { &*component };
+
+        component.downcast_ref::<T>().unwrap()
+    }
+}
+
+impl<T: 'static> QueryData for &mut T {
+    type Item<'a> = &'a mut T;
+
+    fn accesses(access: &mut AccessMap) {
+        if access
+            .insert(AccessKey::component::<T>(), Access::Write)
+            .is_some()
+        {
+            panic!(
+                "conflicting access in system; attempting to access {} mutably twice",
+                std::any::type_name::<T>()
+            );
+        }
+    }
+
+    fn matches(entities: &Entities, entity: Entity) -> bool {
+        entities.cell::<T>(entity).is_some()
+    }
+
+    unsafe fn fetch(entities: &Entities, entity: Entity) -> &mut T {
+        let component = entities.cell::<T>(entity).unwrap().get();
+
+        // SAFETY: The caller asserts that nothing else is looking at this component.
+        let component = unsafe { &mut *component };
+
+        component.downcast_mut::<T>().unwrap()
+    }
+}
+
+macro_rules! impl_query_data {
+    (
+        $($params:ident),*
+    ) => {
+        #[allow(non_snake_case)]
+        #[allow(unused)]
+        #[allow(clippy::unused_unit)]
+        impl<$($params: QueryData),*> QueryData for ($($params,)*) {
+            type Item<'a> = ($($params::Item<'a>,)*);
+
+            fn accesses(access: &mut AccessMap) {
+                $(
+                    $params::accesses(access);
+                )*
+            }
+
+            fn matches(entities: &Entities, entity: Entity) -> bool {
+                true $(&& $params::matches(entities, entity))*
+            }
+
+            unsafe fn fetch<'a>(entities: &'a Entities, entity: Entity) -> Self::Item<'a> {
+                // SAFETY:
+                // The caller upholds the contract for all of us together, and `accesses` has
+                // checked that no two elements conflict with each other.
+                ($(unsafe { $params::fetch(entities, entity) },)*)
+            }
+        }
+    }
+}
+
+call_for_each_arity!(impl_query_data; T1, T2, T3, T4, T5, T6, T7, T8);
+
+/// Gives a system access to the components of every entity that has all of the ones in `D`, such
+/// as `Query<(&Position, &mut Velocity)>`.
+struct Query<'a, D: QueryData> {
+    /// `None` until the first entity is spawned.
+    entities: Option<&'a Entities>,
+    marker: PhantomData<fn() -> D>,
+}
+
+impl<D: QueryData> Query<'_, D> {
+    /// Every matching entity's components, in the order the entities were spawned.
+    ///
+    /// Taking `&mut self` keeps the items from outliving the iterator, so that a `&mut` component
+    /// from one call can't still be around during the next.
+    pub fn iter(&mut self) -> impl Iterator<Item = D::Item<'_>> + '_ {
+        let entities = self.entities;
+        entities
+            .into_iter()
+            .flat_map(|entities| entities.alive.iter().map(move |&entity| (entities, entity)))
+            .filter(|&(entities, entity)| D::matches(entities, entity))
+            // SAFETY:
+            // The entity matches, and every entity comes up once, so items never share a
+            // component. `SystemParam::accesses` made sure nothing else in the system can touch
+            // these component types.
+            .map(|(entities, entity)| unsafe { D::fetch(entities, entity) })
+    }
+}
+
+impl<'res, D: QueryData> SystemParam for Query<'res, D> {
+    type State = ();
+    type Item<'new> = Query<'new, D>;
+
+    fn accesses(access: &mut AccessMap) {
+        D::accesses(access);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        _state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        Ok(Query {
+            entities: Entities::get(resources),
+            marker: PhantomData,
+        })
+    }
+}
+// ANCHOR_END: Query
+
+// ANCHOR: SeqLock
+/// Types a seqlock can hold. They're copied in and out of the lock a word at a time, as plain
+/// integers, so every byte of them has to be initialized.
+///
+/// # Safety
+/// Implementors must not have any padding bytes.
+unsafe trait SeqLockValue: Copy + Send + Sync + 'static {}
+
+macro_rules! seqlock_value {
+    ($($ty:ty),*) => {
+        $(
+            // SAFETY: Primitives don't have padding.
+            unsafe impl SeqLockValue for $ty {}
+        )*
+    };
+}
+
+seqlock_value!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+// SAFETY: The elements of an array sit right next to each other, so it has no padding of its own.
+unsafe impl<T: SeqLockValue, const N: usize> SeqLockValue for [T; N] {}
+
+/// The shared half of a seqlock: a value with one writer somewhere, which any number of readers
+/// on any threads can read without ever blocking. Cloning it clones the handle, not the value.
+/// Created with [`seqlock`].
+struct SeqLockReader<T: SeqLockValue> {
+    inner: Arc<SeqLockInner<T>>,
+}
+
+/// The only handle that can write to a seqlock. It can't be cloned, which is what makes sure
+/// there's only ever one writer.
+struct SeqLockWriter<T: SeqLockValue> {
+    inner: Arc<SeqLockInner<T>>,
+}
+
+struct SeqLockInner<T> {
+    /// Odd while a write is in progress. Bumped twice per write.
+    sequence: AtomicUsize,
+    /// The bytes of the value, a word at a time. The last word is padded with zeroes.
+    words: Box<[AtomicUsize]>,
+    value: PhantomData<T>,
+}
+
+const WORD: usize = std::mem::size_of::<usize>();
+
+fn seqlock<T: SeqLockValue>(value: T) -> (SeqLockWriter<T>, SeqLockReader<T>) {
+    let words = std::mem::size_of::<T>().div_ceil(WORD);
+    let inner = Arc::new(SeqLockInner {
+        sequence: AtomicUsize::new(0),
+        words: (0..words).map(|_| AtomicUsize::new(0)).collect(),
+        value: PhantomData,
+    });
+    inner.store(value);
+
+    (
+        SeqLockWriter {
+            inner: inner.clone(),
+        },
+        SeqLockReader { inner },
+    )
+}
+
+impl<T: SeqLockValue> SeqLockInner<T> {
+    fn store(&self, value: T) {
+        // SAFETY:
+        // `T: SeqLockValue` means there's no padding, so every byte of `value` is initialized.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&value as *const T as *const u8, std::mem::size_of::<T>())
+        };
+
+        for (word, chunk) in self.words.iter().zip(bytes.chunks(WORD)) {
+            let mut buffer = [0; WORD];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            word.store(usize::from_ne_bytes(buffer), Ordering::Relaxed);
+        }
+    }
+
+    /// Copies the words out. If a write overlapped, the result is a mix of two values, which may
+    /// not be a valid `T` at all, hence the `MaybeUninit`.
+    fn load(&self) -> std::mem::MaybeUninit<T> {
+        let mut value = std::mem::MaybeUninit::<T>::uninit();
+        let bytes = value.as_mut_ptr() as *mut u8;
+
+        for (index, word) in self.words.iter().enumerate() {
+            let word = word.load(Ordering::Relaxed).to_ne_bytes();
+            let len = WORD.min(std::mem::size_of::<T>() - index * WORD);
+            // SAFETY:
+            // There are just enough words to hold a `T`, and only the last one is cut short, so
+            // this stays inside `value`.
+            unsafe { std::ptr::copy_nonoverlapping(word.as_ptr(), bytes.add(index * WORD), len) };
+        }
+
+        value
+    }
+}
+
+impl<T: SeqLockValue> Clone for SeqLockReader<T> {
+    fn clone(&self) -> Self {
+        SeqLockReader {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: SeqLockValue> SeqLockReader<T> {
+    /// Copies the current value out. Spins, without ever taking a lock, while a write is in
+    /// progress.
+    pub fn read(&self) -> T {
+        let inner = &*self.inner;
+        loop {
+            // Acquire pairs with the writer's final Release store: if we see an even sequence
+            // number, we also see everything written before it.
+            let before = inner.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let value = inner.load();
+
+            // This fence keeps the loads of the words above from moving below the check below.
+            std::sync::atomic::fence(Ordering::Acquire);
+            let after = inner.sequence.load(Ordering::Relaxed);
+            if before == after {
+                // SAFETY:
+                // No write overlapped the loads, so the words held every byte of the `T` the last
+                // write stored.
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+}
+
+impl<T: SeqLockValue> SeqLockWriter<T> {
+    pub fn write(&mut self, value: T) {
+        let inner = &*self.inner;
+        let sequence = inner.sequence.load(Ordering::Relaxed);
+        inner.sequence.store(sequence + 1, Ordering::Relaxed);
+        // Keeps the stores of the words below from moving above the odd sequence number, so a
+        // reader that sees the old, even number can't also see part of the new value undetected.
+        std::sync::atomic::fence(Ordering::Release);
+
+        // `&mut self` and the writer not being `Clone` mean nothing else is storing at the same
+        // time.
+        inner.store(value);
+
+        inner.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
+
+/// The latest value of a seqlock whose [`SeqLockReader`] was added as a resource. Read once when
+/// the system starts, so it doesn't change halfway through a run even if the writer moves on.
+struct SeqLockRes<T: SeqLockValue> {
+    value: T,
+}
+
+impl<T: SeqLockValue> Deref for SeqLockRes<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: SeqLockValue> SystemParam for SeqLockRes<T> {
+    type State = ();
+    type Item<'new> = SeqLockRes<T>;
+
+    fn accesses(access: &mut AccessMap) {
+        Res::<SeqLockReader<T>>::accesses(access);
+    }
+
+    fn requires(required: &mut Vec<(TypeId, &'static str)>) {
+        Res::<SeqLockReader<T>>::requires(required);
+    }
+
+    unsafe fn retrieve<'r>(
+        resources: &'r TypeMap,
+        state: &'r mut (),
+    ) -> Result<Self::Item<'r>, SystemParamError> {
+        // SAFETY: We recorded the same access as `Res`, so the caller's promise covers it too.
+        let reader = unsafe { Res::<SeqLockReader<T>>::retrieve(resources, state) }?;
+
+        Ok(SeqLockRes {
+            value: reader.read(),
+        })
+    }
+}
+// ANCHOR_END: SeqLock
+
+// ANCHOR: ParallelScheduler
+/// Runs systems that don't conflict with each other at the same time, on separate threads. Every
+/// resource has to be `Send + Sync` and every system `Send`, which is why this is its own type
+/// rather than a method on [`Scheduler`].
+#[derive(Default)]
+struct ParallelScheduler {
+    systems: Vec<ParallelSystem>,
+    resources: TypeMap,
+    resource_names: HashMap<TypeId, &'static str>,
+    /// How to clone and compare the resources added with
+    /// [`ParallelScheduler::add_verified_resource`].
+    verifiers: HashMap<TypeId, Verifier>,
+}
+
+struct ParallelSystem {
+    system: Box<dyn System<In = (), Out = ()> + Send>,
+    accesses: AccessMap,
+    /// Names of the systems this one has to run before, like [`StoredSystem::before`].
+    before: Vec<Cow<'static, str>>,
+    /// Names of the systems this one has to run after.
+    after: Vec<Cow<'static, str>>,
+}
+
+/// Lets the threads of one batch share the resources.
+struct SharedResources<'a>(&'a TypeMap);
+
+// SAFETY:
+// `ParallelScheduler::add_resource` only accepts `Send + Sync` resources, so the values themselves
+// may be used from any thread. Which thread gets to use which value is up to the batches: see
+// `run_parallel`.
+unsafe impl Sync for SharedResources<'_> {}
+
+/// A system can't be added to a [`ParallelScheduler`], because it needs something only
+/// [`Scheduler`] has, like the queue behind `Commands`.
+#[derive(Debug, PartialEq, Eq)]
+struct SchedulerOnly {
+    /// The name of the system.
+    system: Cow<'static, str>,
+    /// The type name of what it needs.
+    needs: &'static str,
+}
+
+impl std::fmt::Display for SchedulerOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "system {} needs {}, which only `Scheduler` has, so it can't run on a \
+             `ParallelScheduler`",
+            self.system, self.needs,
+        )
+    }
+}
+
+impl ParallelScheduler {
+    /// Adds a system, unless it takes a parameter that needs the single-threaded [`Scheduler`],
+    /// such as `Commands` or `ResOwned`.
+    pub fn add_system<I, S: System<In = (), Out = ()> + Send + 'static>(
+        &mut self,
+        system: impl IntoSystem<I, System = S>,
+    ) -> Result<SystemId, SchedulerOnly> {
+        let SystemDescriptor {
+            system,
+            before,
+            after,
+        } = system.into_descriptor();
+
+        let mut required = Vec::new();
+        system.requires(&mut required);
+        if let Some(&(_, needs)) = required
+            .iter()
+            .find(|(id, _)| *id == TypeId::of::<CommandQueue>())
+        {
+            return Err(SchedulerOnly {
+                system: system.name(),
+                needs,
+            });
+        }
+
+        let mut accesses = AccessMap::new();
+        system.accesses(&mut accesses);
+
+        let id = SystemId(self.systems.len());
+        self.systems.push(ParallelSystem {
+            system: Box::new(system),
+            accesses,
+            before,
+            after,
+        });
+        Ok(id)
+    }
+
+    pub fn add_resource<R: Send + Sync + 'static>(&mut self, res: R) {
+        self.resources
+            .insert(TypeId::of::<R>(), UnsafeCell::new(Box::new(res)));
+        self.resource_names
+            .insert(TypeId::of::<R>(), std::any::type_name::<R>());
+    }
+
+    pub fn get_resource_mut<R: 'static>(&mut self) -> Option<&mut R> {
+        let value = self.resources.get_mut(&TypeId::of::<R>())?.get_mut();
+        value.downcast_mut()
+    }
+
+    /// Groups the systems into batches that can each run all at once. A system goes in the batch
+    /// right after the last one holding a system it conflicts with or has to run after. Two
+    /// systems that conflict without a constraint between them still run in the order they were
+    /// added. Panics if the constraints form a cycle, like [`Scheduler::schedule_order`].
+    pub fn batches(&self) -> Vec<Vec<SystemId>> {
+        let len = self.systems.len();
+
+        // `precedes[a]` lists the systems that have to wait for `a` because of `.before()` and
+        // `.after()`, the same as `must_precede` in `schedule_order`.
+        let mut precedes = vec![Vec::new(); len];
+        let names: Vec<_> = self
+            .systems
+            .iter()
+            .map(|system| system.system.name())
+            .collect();
+        let named = |name: &Cow<'static, str>| -> Vec<usize> {
+            (0..len).filter(|&index| names[index] == *name).collect()
+        };
+        for (index, system) in self.systems.iter().enumerate() {
+            for later in system.before.iter().flat_map(named) {
+                precedes[index].push(later);
+            }
+            for earlier in system.after.iter().flat_map(named) {
+                precedes[earlier].push(index);
+            }
+        }
+
+        if let Some(cycle) = find_cycle(&precedes) {
+            let names: Vec<_> = cycle.iter().map(|&index| names[index].as_ref()).collect();
+            panic!(
+                "systems have ordering constraints that form a cycle: {}",
+                names.join(" -> "),
+            );
+        }
+
+        let mut waiting = vec![0; len];
+        for &later in precedes.iter().flatten() {
+            waiting[later] += 1;
+        }
+
+        // Systems are placed in an order that keeps to the constraints, earliest-added first, so
+        // everything a system has to wait for already has a batch by the time it's placed.
+        let mut batch_of: Vec<Option<usize>> = vec![None; len];
+        let mut batches: Vec<Vec<SystemId>> = Vec::new();
+        while let Some(next) =
+            (0..len).find(|&index| batch_of[index].is_none() && waiting[index] == 0)
+        {
+            let system = &self.systems[next];
+            let batch = (0..len)
+                .filter_map(|other| {
+                    let waits = precedes[other].contains(&next)
+                        || conflicts(&self.systems[other].accesses, &system.accesses);
+                    batch_of[other].filter(|_| waits)
+                })
+                .max()
+                .map_or(0, |last| last + 1);
+
+            if batch == batches.len() {
+                batches.push(Vec::new());
+            }
+            batches[batch].push(SystemId(next));
+            batch_of[next] = Some(batch);
+            for &later in &precedes[next] {
+                waiting[later] -= 1;
+            }
+        }
+        batches
+    }
+
+    /// Runs every system once, running each batch's systems in parallel. Panics if a system's
+    /// parameters aren't available, or if a system panics.
+    pub fn run_parallel(&mut self) {
+        let batches = self.batches();
+        let resources = SharedResources(&self.resources);
+
+        for batch in batches {
+            let mut systems: Vec<_> = self
+                .systems
+                .iter_mut()
+                .enumerate()
+                .filter(|(index, _)| batch.contains(&SystemId(*index)))
+                .map(|(_, system)| &mut system.system)
+                .collect();
+
+            // Only systems from the same batch run at the same time, and no two of them conflict.
+            // So a resource one of them gets mutably isn't touched by any other thread until the
+            // scope ends, and resources that are shared are only ever read. It's the same argument
+            // `retrieve` relies on for systems running one after another, just across threads.
+            // The private resources that need systems to run one at a time, like `CommandQueue`,
+            // are never added here.
+            std::thread::scope(|scope| {
+                for system in systems.iter_mut() {
+                    let resources = &resources;
+                    scope.spawn(move || system.run((), resources.0));
+                }
+            });
+        }
+    }
+}
+
+/// Whether two systems would touch the same resource with at least one of them writing it.
+fn conflicts(a: &AccessMap, b: &AccessMap) -> bool {
+    a.iter().any(|(id, access)| match b.get(id) {
+        Some(other) => *access == Access::Write || *other == Access::Write,
+        None => false,
+    })
+}
+// ANCHOR_END: ParallelScheduler
+
+// ANCHOR: RunVerified
+/// Type-erased `Clone`, `PartialEq` and `Debug` for one type of resource.
+struct Verifier {
+    clone: fn(&dyn Any) -> Box<dyn Any>,
+    eq: fn(&dyn Any, &dyn Any) -> bool,
+    debug: fn(&dyn Any) -> String,
+}
+
+impl Verifier {
+    fn of<R: Clone + PartialEq + std::fmt::Debug + 'static>() -> Self {
+        Verifier {
+            clone: |value| Box::new(value.downcast_ref::<R>().unwrap().clone()),
+            eq: |a, b| a.downcast_ref::<R>() == b.downcast_ref::<R>(),
+            debug: |value| format!("{:?}", value.downcast_ref::<R>().unwrap()),
+        }
+    }
+}
+
+impl ParallelScheduler {
+    /// Adds a resource that [`ParallelScheduler::run_verified`] can copy and compare.
+    pub fn add_verified_resource<R>(&mut self, res: R)
+    where
+        R: Send + Sync + Clone + PartialEq + std::fmt::Debug + 'static,
+    {
+        self.add_resource(res);
+        self.verifiers
+            .insert(TypeId::of::<R>(), Verifier::of::<R>());
+    }
+
+    /// A debugging aid for finding accesses that systems don't report. Runs every system one
+    /// after another on a copy of the resources, then runs the schedule in parallel as usual, and
+    /// panics if the two runs ended up with different resources.
+    ///
+    /// Every resource has to be added with [`ParallelScheduler::add_verified_resource`]. Systems
+    /// run twice per call, so ones with [`Local`] state see every frame twice.
+    pub fn run_verified(&mut self) {
+        let mut copy = TypeMap::new();
+        for (id, cell) in self.resources.iter_mut() {
+            let Some(verifier) = self.verifiers.get(id) else {
+                panic!(
+                    "resource {} can't be verified; add it with `add_verified_resource`",
+                    self.resource_names[id],
+                );
+            };
+            copy.insert(*id, UnsafeCell::new((verifier.clone)(&**cell.get_mut())));
+        }
+
+        for system in self.systems.iter_mut() {
+            system.system.run((), &copy);
+        }
+        self.run_parallel();
+
+        for (id, cell) in self.resources.iter_mut() {
+            let verifier = &self.verifiers[id];
+            let parallel = &**cell.get_mut();
+            let sequential = &**copy.get_mut(id).unwrap().get_mut();
+            assert!(
+                (verifier.eq)(parallel, sequential),
+                "resource {} differs between running in parallel ({}) and one after another \
+                 ({}), so some system must be accessing it without saying so",
+                self.resource_names[id],
+                (verifier.debug)(parallel),
+                (verifier.debug)(sequential),
+            );
+        }
+    }
+}
+// ANCHOR_END: RunVerified
+// ANCHOR_END: All